@@ -0,0 +1,81 @@
+use execute::ProgramState;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/* <output> or the program's file name without extension, in the current directory */
+fn output_path(cmdline: &super::parser::CmdLine, itr: usize, arg: &str) -> PathBuf { 
+    match &cmdline.output { 
+        Some(output) => PathBuf::from(super::per_program(output, itr, &cmdline.programs)),
+        None => { 
+            let stem = Path::new(arg).file_stem().unwrap_or_default();
+            match cmdline.emit { 
+                super::Emit::Object => Path::new(stem).with_extension("o"),
+                super::Emit::Wat => Path::new(stem).with_extension("wat"),
+                super::Emit::Wasm => Path::new(stem).with_extension("wasm"),
+                super::Emit::Disasm => Path::new(stem).with_extension("dis"),
+                super::Emit::Executable | super::Emit::Elf => PathBuf::from(stem),
+            }
+        },
+    }
+}
+
+pub fn run(cmdline: &super::parser::CmdLine, heap_size: usize) -> std::io::Result<()> { 
+    let options = super::diff::parse_options(cmdline.config.as_deref().unwrap_or(""), heap_size)?;
+
+    /* other targets can only be compiled, linking and the wasm backends are host or target independent */
+    let target = match (&cmdline.target, &cmdline.emit) { 
+        (None, _) => None,
+        (Some(target), super::Emit::Object | super::Emit::Disasm) => Some(target.as_str()),
+        (Some(_), _) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--target only works with --emit obj or --emit disasm")),
+    };
+
+    for (itr, arg) in cmdline.programs.iter().enumerate() {
+        let src = std::fs::read(arg)?;
+        let prog = match ProgramState::with_options(&src, &options) { 
+            Ok(program) => program,
+            Err(err) => { 
+                println!("Error compiling {} to byte code : {}", arg, err);
+                continue;
+            },
+        };
+        let output = output_path(cmdline, itr, arg);
+        let isa = || match target { 
+            Some(target) => execute::target_isa(target, true),
+            None => execute::host_isa(true),
+        };
+        let built = match cmdline.emit { 
+            super::Emit::Elf => execute::elf::executable(&prog),
+            super::Emit::Wat => Ok(execute::wasm::wat(&prog).into_bytes()),
+            super::Emit::Wasm => Ok(execute::wasm::wasm(&prog)),
+            super::Emit::Disasm => isa().and_then(|isa| execute::aot::disassemble(&prog, &*isa)).map(String::into_bytes),
+            _ => isa().and_then(|isa| execute::aot::object_for(&prog, arg, isa)),
+        };
+        let built = match built { 
+            Ok(built) => built,
+            Err(err) => { 
+                println!("Error compiling {} : {}", arg, err);
+                continue;
+            },
+        };
+
+        match cmdline.emit { 
+            super::Emit::Object | super::Emit::Wat | super::Emit::Wasm | super::Emit::Disasm => std::fs::write(&output, built)?,
+            super::Emit::Elf => { 
+                std::fs::write(&output, built)?;
+                #[cfg(unix)]
+                std::fs::set_permissions(&output, std::fs::Permissions::from_mode(0o755))?;
+            },
+            super::Emit::Executable => { 
+                let mut obj_path = output.clone().into_os_string();
+                obj_path.push(".o");
+                std::fs::write(&obj_path, built)?;
+                let linked = execute::aot::link(obj_path.as_ref(), &output);
+                let _ = std::fs::remove_file(&obj_path);
+                linked?;
+            },
+        }
+        println!("prog[{}][{}] built {}", itr, arg, output.display());
+    }
+    Ok(())
+}
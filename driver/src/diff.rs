@@ -0,0 +1,156 @@
+use execute::diff::{Config, DivergenceKind, Engine, SideState};
+use execute::{CellWidth, EofPolicy, Error, Options, ProgramState, TapeModel};
+
+fn bad_config(spec: &str, why: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("bad configuration '{}': {}", spec, why))
+}
+
+/* engine=CraneLift,peephole=off,cell=16,tape=bounded,heap=4096,eof=unchanged */
+fn parse_config(spec: &str, engine: Engine, heap_size: usize) -> std::io::Result<Config> {
+    let mut cfg = Config { engine, options: Options::new(heap_size) };
+
+    for item in spec.split(',').filter(|x| !x.is_empty()) {
+        match item { 
+            "engine=Interpreter" => cfg.engine = Engine::Interpreter,
+            "engine=CraneLift" => cfg.engine = Engine::CraneLift,
+            _ => parse_option(&mut cfg.options, spec, item)?,
+        }
+    }
+    if cfg.engine == Engine::Interpreter && cfg.options.cell_width != CellWidth::W8 {
+        return Err(bad_config(spec, "the interpreter only supports 8 bit cells, use engine=CraneLift"));
+    }
+    Ok(cfg)
+}
+
+/* A config without engine=, as taken by run and build */
+pub fn parse_options(spec: &str, heap_size: usize) -> std::io::Result<Options> {
+    let mut options = Options::new(heap_size);
+    for item in spec.split(',').filter(|x| !x.is_empty()) {
+        parse_option(&mut options, spec, item)?;
+    }
+    Ok(options)
+}
+
+fn parse_option(options: &mut Options, spec: &str, item: &str) -> std::io::Result<()> {
+    let (key, val) = item.split_once('=').ok_or_else(|| bad_config(spec, "expected key=value"))?;
+    match (key, val) {
+        ("peephole", "on") => options.peephole = true,
+        ("peephole", "off") => options.peephole = false,
+        ("cell", "8") => options.cell_width = CellWidth::W8,
+        ("cell", "16") => options.cell_width = CellWidth::W16,
+        ("cell", "32") => options.cell_width = CellWidth::W32,
+        ("tape", "wrap") => options.tape = TapeModel::Wrap,
+        ("tape", "bounded") => options.tape = TapeModel::Bounded,
+        ("heap", n) => options.heap_size = n.parse().map_err(|_| bad_config(spec, "heap expects a number"))?,
+        ("eof", "zero") => options.eof = EofPolicy::Zero,
+        ("eof", "minus-one") => options.eof = EofPolicy::MinusOne,
+        ("eof", "unchanged") => options.eof = EofPolicy::Unchanged,
+        _ => return Err(bad_config(spec, item)),
+    }
+    Ok(())
+}
+
+fn describe(cfg: &Config) -> String {
+    format!("{:?} peephole={} cell={} tape={:?} heap={} eof={:?}",
+        cfg.engine,
+        if cfg.options.peephole { "on" } else { "off" },
+        cfg.options.cell_width.bytes() * 8,
+        cfg.options.tape,
+        cfg.options.heap_size,
+        cfg.options.eof)
+}
+
+fn snippet(src: &[u8], span: &std::ops::Range<usize>) -> String {
+    let text = String::from_utf8_lossy(&src[span.clone()]);
+    if text.len() > 24 { format!("{}...", &text[..24]) } else { text.to_string() }
+}
+
+fn print_side(name: &str, cfg: &Config, side: &SideState, prog: &ProgramState, src: &[u8]) {
+    println!("  {}: {}", name, describe(cfg));
+    match (&side.at, &side.finished) {
+        (Some(at), _) => {
+            let (line, col) = prog.srcmap.line_col(at.src);
+            println!("     at {}:{} '{}' IR #{} {:?}, ptr = {}", line, col, snippet(src, &prog.spans[at.idx]), at.idx, prog.txt[at.idx], at.ptr);
+
+            /* ops run since the previous sync point, when that was straight-line code */
+            let from = match side.prev_idx {
+                Some(prev) if prev < at.idx => prev + 1,
+                _ => at.idx,
+            };
+            for idx in from..at.idx {
+                let (line, col) = prog.srcmap.line_col(prog.spans[idx].start);
+                println!("       IR #{:<6} {:<16} {}:{} '{}'", idx, format!("{:?}", prog.txt[idx]), line, col, snippet(src, &prog.spans[idx]));
+            }
+        },
+        (None, Some(Ok(()))) => println!("     finished"),
+        (None, Some(Err(err))) => println!("     failed: {}", err),
+        (None, None) => (),
+    }
+    println!("     output ({} bytes): {:?}", side.output.len(), String::from_utf8_lossy(&side.output[side.output.len().saturating_sub(40)..]));
+}
+
+/* Exits with status 1 if any program diverged and 2 if any could not be run, as diff(1) does */
+pub fn run(cmdline: &super::parser::CmdLine, heap_size: usize) -> std::io::Result<()> {
+    match run_all(cmdline, heap_size) {
+        Ok((false, false)) => Ok(()),
+        Ok((true, false)) => std::process::exit(1),
+        Ok((_, true)) => std::process::exit(2),
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(2);
+        },
+    }
+}
+
+/* Whether any program diverged and whether any failed */
+fn run_all(cmdline: &super::parser::CmdLine, heap_size: usize) -> std::io::Result<(bool, bool)> {
+    let cfg_a = parse_config(cmdline.configs.0.as_deref().unwrap_or(""), Engine::Interpreter, heap_size)?;
+    let cfg_b = parse_config(cmdline.configs.1.as_deref().unwrap_or(""), Engine::CraneLift, heap_size)?;
+    let input = match &cmdline.input {
+        Some(file) => std::fs::read(file)?,
+        None => Vec::new(),
+    };
+
+    let (mut diverged, mut failed) = (false, false);
+    for (itr, arg) in cmdline.programs.iter().enumerate() {
+        let src = std::fs::read(arg)?;
+        let (result, prog_a, prog_b) = match execute::diff::diff(&src, &input, &cfg_a, &cfg_b, cmdline.limit) {
+            Ok(result) => result,
+            Err(err @ Error::Nesting { .. }) => {
+                println!("Error compiling {} to byte code : {}", arg, err);
+                failed = true;
+                continue;
+            },
+            Err(err) => {
+                println!("Error running {} : {}", arg, err);
+                failed = true;
+                continue;
+            },
+        };
+
+        let divergence = match result.divergence {
+            None if result.truncated => {
+                println!("prog[{}][{}]: no divergence in the first {} sync points", itr, arg, result.checkpoints);
+                continue;
+            },
+            None => {
+                println!("prog[{}][{}]: configurations agree ({} sync points)", itr, arg, result.checkpoints);
+                continue;
+            },
+            Some(divergence) => divergence,
+        };
+
+        let what = match &divergence.kind {
+            DivergenceKind::Location => "different sync points reached".to_string(),
+            DivergenceKind::Pointer => "pointer differs".to_string(),
+            DivergenceKind::Output { pos } => format!("output differs at byte {}", pos),
+            DivergenceKind::Tape { cell, a, b } => format!("tape differs at cell {}: a = {}, b = {}", cell, a, b),
+            DivergenceKind::Finished => "only one side finished".to_string(),
+        };
+        diverged = true;
+        println!("prog[{}][{}]: diverged after {} matching sync points, {}", itr, arg, result.checkpoints, what);
+        print_side("a", &cfg_a, &divergence.a, &prog_a, &src);
+        print_side("b", &cfg_b, &divergence.b, &prog_b, &src);
+    }
+    Ok((diverged, failed))
+}
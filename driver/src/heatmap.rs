@@ -0,0 +1,49 @@
+use execute::heatmap::{static_range, Bucket, TapeStats};
+use execute::ProgramState;
+
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+/* one character per bucket, darker for more accesses on a log scale */
+fn shade(buckets: &[Bucket], count: fn(&Bucket) -> u64) -> String { 
+    let max = buckets.iter().map(count).max().unwrap_or(0);
+    buckets.iter()
+        .map(|bucket| match count(bucket) { 
+            0 => ' ',
+            n => { 
+                let level = ((n as f64).ln_1p() / (max as f64).ln_1p() * (SHADES.len() - 2) as f64).round() as usize;
+                SHADES[1 + level] as char
+            },
+        })
+        .collect()
+}
+
+pub fn print(prog: &ProgramState, stats: &TapeStats, heap_size: usize) { 
+    println!("tape"); 
+    println!(" cells touched: {}", stats.touched()); 
+    println!(" pointer range: {}..={} relative to the start ({} cells)", stats.lowest, stats.highest, stats.span()); 
+    if stats.lowest < 0 { 
+        println!(" the pointer moved left of its starting cell, this only works on a wrapping tape"); 
+    }
+    println!(" suggested tape size: {} cells (current {})", stats.suggested_size(), heap_size); 
+    match static_range(&prog.txt) { 
+        Ok(range) => println!(" static estimate: {}..={}", range.start(), range.end()),
+        Err(idx) => { 
+            let (line, col) = prog.srcmap.line_col(prog.spans[idx].start);
+            println!(" static estimate: unknown, the loop at {}:{} moves the pointer", line, col);
+        },
+    }
+
+    let buckets = stats.heatmap(64);
+    if let (Some(first), Some(last)) = (buckets.first(), buckets.last()) { 
+        println!(" heatmap: cells {}..{}, {} per column", first.cells.start, last.cells.end, first.cells.len()); 
+        println!("  reads  |{}|", shade(&buckets, |bucket| bucket.reads)); 
+        println!("  writes |{}|", shade(&buckets, |bucket| bucket.writes)); 
+    }
+
+    let mut hot: Vec<usize> = (0..stats.reads.len()).filter(|cell| stats.reads[*cell] + stats.writes[*cell] > 0).collect();
+    hot.sort_by_key(|cell| std::cmp::Reverse(stats.reads[*cell] + stats.writes[*cell]));
+    println!(" hottest cells:"); 
+    for cell in hot.into_iter().take(5) { 
+        println!("{:>8} reads {:>12} writes {:>12}", cell, stats.reads[cell], stats.writes[cell]); 
+    }
+}
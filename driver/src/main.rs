@@ -3,58 +3,157 @@ extern crate execute;
 
 use std::env; 
 
+mod build;
+mod diff;
+mod heatmap;
+mod profile;
+mod transpile;
+
 const HEAPSIZE: usize = 2 * 1024; 
-type ProgType = Result<execute::ProgramState, execute::NestingErr>;
+type ProgType = Result<execute::ProgramState, execute::Error>;
 
 #[derive(Debug,Default)]
 enum ExecutionEngine { 
     #[default] 
     Interpreter,
     CraneLift,
+    Tiered,     /* interpreter that compiles hot loops */
+}
+
+#[derive(Debug,Default,PartialEq)]
+enum Command { 
+    #[default] 
+    Run,
+    TraceText,
+    Diff,
+    ProfileDiff,
+    Build,
+    Transpile,
+}
+
+#[derive(Debug,Default,PartialEq)]
+enum Emit { 
+    #[default] 
+    Executable,
+    Object,
+    Elf,        /* static executable written without a linker */
+    Wat,
+    Wasm,
+    Disasm,     /* Cranelift's listing of the machine code */
 }
 
 mod parser {
     use std::process; 
 
-    pub fn new_program(bytestream: &[u8], heapsz: usize) -> super::ProgType {
-        execute::ProgramState::new(bytestream, heapsz)
+    pub fn new_program(bytestream: &[u8], options: &execute::Options) -> super::ProgType {
+        execute::ProgramState::with_options(bytestream, options)
     }
 
     #[derive(Debug)]
     pub struct CmdLine {
+        pub command: super::Command,
         pub exec_engine: super::ExecutionEngine,
         pub programs: Vec<String>,
         pub clir: bool,
+        pub trace: Option<String>,
+        pub trace_filter: execute::trace::TraceFilter,
+        pub coverage: Option<String>,
+        pub configs: (Option<String>, Option<String>),
+        pub config: Option<String>,
+        pub output: Option<String>,
+        pub emit: super::Emit,
+        pub target: Option<String>,
+        pub spans: bool,
+        pub input: Option<String>,
+        pub limit: Option<u64>,
+        pub profile: bool,
+        pub profile_out: Option<String>,
+        pub tape_stats: bool,
+        pub top: usize,
+        pub tier_threshold: u64,
+        pub fallback: bool,
+        pub fuel: Option<u64>,
+        pub jit: execute::JitOptions,
+        pub code_cache: Option<execute::cache::CodeCache>,
+        pub perf_map: bool,
+        pub jitdump: bool,
     }
 
-    impl  CmdLine { 
-        fn new(engine: super::ExecutionEngine, programs: Vec<String>) -> Self {
-            CmdLine { 
-                exec_engine: engine, 
-                programs: programs.clone(),
-                clir: false,
-            }
-        }
-    } 
-
     pub fn usage(prog: &str) {
-        println!("Usage: {} [-e <Interpreter/CraneLift>  [list of BF programs]", prog); 
+        println!("Usage: {} [-e <Interpreter/CraneLift/Tiered>  [list of BF programs]", prog); 
+        println!("       {} trace-text [list of trace files]", prog); 
+        println!("       {} diff [-a <config>] [-b <config>] [--input <file>] [list of BF programs]", prog); 
+        println!("         <config> is a comma separated list of engine=<Interpreter/CraneLift>, peephole=<on/off>,"); 
+        println!("         cell=<8/16/32>, tape=<wrap/bounded>, heap=<cells>, eof=<zero/minus-one/unchanged>"); 
+        println!("       {} build [--config <config>] [--emit <exe/obj/elf/wat/wasm/disasm>] [--target <triple>[,<isa flag>...]] [-o <output>] [list of BF programs]", prog); 
+        println!("       {} emit [--target <c/rust/js/python/asm>] [--config <config>] [--spans] [-o <output>] [list of BF programs]", prog); 
+        println!("       {} profile-diff [--top <count>] <a.json> <b.json>", prog); 
     }
 
-    pub fn parse_cmdline<'a,'b>(program_name: &'a str, args: &Vec<String>) -> Result<CmdLine,getopts::Fail> { 
+    fn parse_range<T: std::str::FromStr>(opt: &str, val: &str) -> Result<std::ops::Range<T>, getopts::Fail> { 
+        let bad_range = || getopts::Fail::UnrecognizedOption(format!("{} {} (expected <start>..<end>)", opt, val));
+        let (start, end) = val.split_once("..").ok_or_else(bad_range)?;
+        Ok(start.parse().map_err(|_| bad_range())?..end.parse().map_err(|_| bad_range())?)
+    }
+
+    pub fn parse_cmdline(program_name: &str, args: &[String]) -> Result<CmdLine,getopts::Fail> { 
         let mut opts = getopts::Options::new(); 
 
         opts.parsing_style(getopts::ParsingStyle::FloatingFrees); 
         opts.optflag("h", "help", "Show this menu"); 
         opts.optflag("v", "verbose", "Displays generated cranelift IR"); 
-        opts.optopt("e", "exec-env", "jit vs interpret", "<Interpreter/CraneLift>"); 
+        opts.optopt("e", "exec-env", "jit vs interpret, Tiered interprets and compiles hot loops", "<Interpreter/CraneLift/Tiered>"); 
+        opts.optopt("", "tier-threshold", "Tiered: iterations before a loop is compiled (default 1000)", "<count>"); 
+        opts.optflag("", "fallback", "CraneLift: run in the interpreter when compilation fails"); 
+        opts.optopt("", "fuel", "Stop with an error after this many ops (Interpreter or Tiered)", "<count>"); 
+        opts.optopt("", "opt-level", "CraneLift/Tiered: Cranelift optimization level (default speed)", "<none/speed/speed_and_size>"); 
+        opts.optopt("", "regalloc", "CraneLift/Tiered: register allocator (default backtracking)", "<backtracking/single_pass>"); 
+        opts.optflag("", "no-verify", "CraneLift/Tiered: skip the CLIF verifier"); 
+        opts.optflag("", "frame-pointers", "CraneLift/Tiered: keep frame pointers"); 
+        opts.optflag("", "probestack", "CraneLift/Tiered: probe the stack in large frames"); 
+        opts.optopt("", "max-function-ops", "CraneLift: split bigger programs into functions of top level ops (default 10000)", "<count>"); 
+        opts.optopt("", "jit-threads", "CraneLift: threads compiling the functions of a split program (default: all cores)", "<count>"); 
+        opts.optflag("", "cache", "CraneLift: keep compiled programs in $XDG_CACHE_HOME/bf-jit and reuse them"); 
+        opts.optopt("", "cache-dir", "CraneLift: code cache directory (implies --cache)", "<dir>"); 
+        opts.optopt("", "cache-size", "CraneLift: evict the least recently used programs over this size (default 256)", "<MiB>"); 
+        opts.optflag("", "perf-map", "CraneLift/Tiered: name compiled functions in /tmp/perf-<pid>.map for perf report"); 
+        opts.optflag("", "jitdump", "CraneLift/Tiered: write compiled code and BF line numbers to /tmp/jit-<pid>.dump for perf inject --jit"); 
+        opts.optopt("", "isa-flags", "CraneLift/Tiered: Cranelift ISA flags of the host, e.g. has_avx=false", "<flag>[=<value>][,...]"); 
+        opts.optopt("t", "trace", "Record an execution trace (Interpreter only)", "<trace file>"); 
+        opts.optopt("", "trace-steps", "Only trace steps in this range", "<start>..<end>"); 
+        opts.optopt("", "trace-src", "Only trace ops built from these source bytes", "<start>..<end>"); 
+        opts.optopt("", "coverage", "Write <prefix>.cov (annotated source) and <prefix>.info (lcov) coverage (Interpreter only)", "<prefix>"); 
+        opts.optflag("p", "profile", "Count executed ops and print a profile after each run"); 
+        opts.optopt("", "profile-out", "Write <prefix>.callgrind, <prefix>.folded and <prefix>.json (implies --profile)", "<prefix>"); 
+        opts.optopt("", "top", "Number of loops and ops listed in the profile (default 20)", "<count>"); 
+        opts.optflag("", "tape-stats", "Print per cell reads/writes, the pointer range and a suggested tape size (Interpreter only)"); 
+        opts.optopt("c", "config", "run/build/emit: configuration without engine= (default peephole=on,cell=8,tape=wrap,eof=zero)", "<config>"); 
+        opts.optopt("o", "output", "build/emit: output file (default: program name)", "<file>"); 
+        opts.optopt("", "emit", "build: executable, relocatable object, linker free ELF executable, WebAssembly module or disassembly (default exe)", "<exe/obj/elf/wat/wasm/disasm>"); 
+        opts.optopt("", "target", "emit: language of the generated source, asm for x86-64 GNU assembly (default c). build: target triple and ISA flags for obj/disasm (default host)", "<c/rust/js/python/asm> or <triple>[,<flag>[=<value>]...]"); 
+        opts.optflag("", "spans", "emit: comment each statement with the line:col of its BF source"); 
+        opts.optopt("a", "config-a", "diff: first configuration (default engine=Interpreter)", "<config>"); 
+        opts.optopt("b", "config-b", "diff: second configuration (default engine=CraneLift)", "<config>"); 
+        opts.optopt("", "input", "diff: file fed to both sides as stdin", "<file>"); 
+        opts.optopt("", "limit", "diff: stop after this many matching sync points", "<count>"); 
 
-        let arg_match = opts.parse(&args[1..])?; 
+        let mut arg_match = opts.parse(&args[1..])?; 
         if arg_match.opt_present("h") {
             usage(program_name);
             process::exit(0);
         } 
 
+        let command = match arg_match.free.first().map(|x| x.as_str()) { 
+            Some("trace-text") => super::Command::TraceText,
+            Some("diff") => super::Command::Diff,
+            Some("profile-diff") => super::Command::ProfileDiff,
+            Some("build") => super::Command::Build,
+            Some("emit") => super::Command::Transpile,
+            _ => super::Command::Run,
+        };
+        if command != super::Command::Run { 
+            arg_match.free.remove(0);
+        }
 
         let exec_env = match arg_match.opt_str("e") { 
             Some(v) if v == "Interpreter" => { 
@@ -63,30 +162,148 @@ mod parser {
             Some(v) if v == "CraneLift" => {
                 super::ExecutionEngine::CraneLift 
             }, 
+            Some(v) if v == "Tiered" => {
+                super::ExecutionEngine::Tiered 
+            }, 
+            None if command != super::Command::Run => super::ExecutionEngine::Interpreter,
             Some(_) | None => {
                 usage(program_name);
                 process::exit(-1);
             },
         };
 
+        let emit = match arg_match.opt_str("emit").as_deref() { 
+            None | Some("exe") => super::Emit::Executable,
+            Some("obj") => super::Emit::Object,
+            Some("elf") => super::Emit::Elf,
+            Some("wat") => super::Emit::Wat,
+            Some("wasm") => super::Emit::Wasm,
+            Some("disasm") => super::Emit::Disasm,
+            Some(other) => return Err(getopts::Fail::UnrecognizedOption(format!("--emit {} (expected exe, obj, elf, wat, wasm or disasm)", other))),
+        };
+
         if arg_match.free.is_empty() { 
             println!("BF program files not provided");
             usage(program_name); 
             process::exit(-1);
         }
+
+        let mut jit = execute::JitOptions { 
+            verify: !arg_match.opt_present("no-verify"),
+            frame_pointers: arg_match.opt_present("frame-pointers"),
+            probestack: arg_match.opt_present("probestack"),
+            isa_flags: arg_match.opt_str("isa-flags").iter()
+                .flat_map(|flags| flags.split(',').map(execute::parse_isa_flag).collect::<Vec<_>>())
+                .collect(),
+            ..Default::default()
+        };
+        if let Some(max_ops) = arg_match.opt_get("max-function-ops")
+            .map_err(|_| getopts::Fail::UnrecognizedOption("--max-function-ops expects a number".to_string()))? { 
+            jit.max_function_ops = max_ops;
+        }
+        if let Some(threads) = arg_match.opt_get("jit-threads")
+            .map_err(|_| getopts::Fail::UnrecognizedOption("--jit-threads expects a number".to_string()))? { 
+            jit.threads = threads;
+        }
+        if let Some(level) = arg_match.opt_str("opt-level") { 
+            jit.opt_level = level.parse()
+                .map_err(|_| getopts::Fail::UnrecognizedOption(format!("--opt-level {} (expected none, speed or speed_and_size)", level)))?;
+        }
+        if let Some(regalloc) = arg_match.opt_str("regalloc") { 
+            jit.regalloc = regalloc.parse()
+                .map_err(|_| getopts::Fail::UnrecognizedOption(format!("--regalloc {} (expected backtracking or single_pass)", regalloc)))?;
+        }
+
+        let cache_size: u64 = arg_match.opt_get_default("cache-size", 256)
+            .map_err(|_| getopts::Fail::UnrecognizedOption("--cache-size expects a number".to_string()))?;
+        let code_cache = match (arg_match.opt_str("cache-dir"), arg_match.opt_present("cache")) { 
+            (Some(dir), _) => Some(dir.into()),
+            (None, true) => Some(execute::cache::CodeCache::default_dir()
+                .ok_or_else(|| getopts::Fail::UnrecognizedOption("--cache without $XDG_CACHE_HOME or $HOME, use --cache-dir".to_string()))?),
+            (None, false) => None,
+        }.map(|dir: std::path::PathBuf| execute::cache::CodeCache::new(dir, cache_size << 20));
+
+        let trace_filter = execute::trace::TraceFilter { 
+            steps: arg_match.opt_str("trace-steps").map(|x| parse_range("--trace-steps", &x)).transpose()?,
+            src: arg_match.opt_str("trace-src").map(|x| parse_range("--trace-src", &x)).transpose()?,
+        };
             
         Ok(CmdLine { 
+            command,
             exec_engine: exec_env,
             programs: arg_match.free.clone(),
             clir: arg_match.opt_present("v"),
+            trace: arg_match.opt_str("t"),
+            trace_filter,
+            coverage: arg_match.opt_str("coverage"),
+            configs: (arg_match.opt_str("a"), arg_match.opt_str("b")),
+            config: arg_match.opt_str("c"),
+            output: arg_match.opt_str("o"),
+            emit,
+            target: arg_match.opt_str("target"),
+            spans: arg_match.opt_present("spans"),
+            input: arg_match.opt_str("input"),
+            limit: arg_match.opt_get("limit")
+                .map_err(|_| getopts::Fail::UnrecognizedOption("--limit expects a number".to_string()))?,
+            profile: arg_match.opt_present("p") || arg_match.opt_present("profile-out"),
+            profile_out: arg_match.opt_str("profile-out"),
+            tape_stats: arg_match.opt_present("tape-stats"),
+            top: arg_match.opt_get_default("top", 20)
+                .map_err(|_| getopts::Fail::UnrecognizedOption("--top expects a number".to_string()))?,
+            tier_threshold: arg_match.opt_get_default("tier-threshold", 1000)
+                .map_err(|_| getopts::Fail::UnrecognizedOption("--tier-threshold expects a number".to_string()))?,
+            fallback: arg_match.opt_present("fallback"),
+            fuel: arg_match.opt_get("fuel")
+                .map_err(|_| getopts::Fail::UnrecognizedOption("--fuel expects a number".to_string()))?,
+            jit,
+            code_cache,
+            perf_map: arg_match.opt_present("perf-map"),
+            jitdump: arg_match.opt_present("jitdump"),
         })
     }
 }
 
+/* Functions of a split program, slowest to compile first */
+fn print_function_stats(prog: &execute::ProgramState, top: usize) { 
+    let stats = prog.code_stats().unwrap();
+    let mut functions: Vec<_> = prog.function_stats().iter().collect();
+    functions.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.compile_time()));
+    println!("{} functions, {:?} compiling on {} threads (build {:?}, verify {:?}, cranelift {:?})", 
+        functions.len(), stats.compile_time(), prog.jit_options.threads, stats.build, stats.verify, stats.compile);
+    println!("{:>12} {:>12} {:>8} {:>6}  function", "compile", "cranelift", "bytes", "loops");
+    for (name, stats) in functions.iter().take(top) { 
+        println!("{:>12?} {:>12?} {:>8} {:>6}  {}", stats.compile_time(), stats.compile, stats.code_size, stats.loops, name);
+    }
+}
+
+/* one output file per program when several are given */
+fn per_program(name: &str, itr: usize, programs: &[String]) -> String { 
+    if programs.len() > 1 { 
+        format!("{}.{}", name, itr) 
+    } else { 
+        name.to_string() 
+    }
+}
+
+/* Show the CLIR of a failed compile, then run in the interpreter if --fallback was given */
+fn jit_fallback(prog: &mut execute::ProgramState, arg: &str, err: execute::Error, cmdline: &parser::CmdLine) -> Result<(i32, std::time::Duration), execute::Error> { 
+    if let (true, Some(clif)) = (cmdline.clir, err.clif()) { 
+        println!("Compiled Code: ====================\n{}", clif); 
+    }
+    if !cmdline.fallback { 
+        return Err(err);
+    }
+    println!("Error compiling {} : {}, falling back to the interpreter", arg, err);
+    match cmdline.profile { 
+        true => prog.interpret_profiled(),
+        false => prog.interpret(),
+    }
+}
+
 fn main() -> std::io::Result<()> {
     /* Command line parsing */
     let args: Vec<String> = env::args().collect();
-    let prog_name = if args.len() > 0 { 
+    let prog_name = if !args.is_empty() { 
         args[0].clone() 
     } else { 
         "bfrs_jit".to_string()
@@ -99,104 +316,158 @@ fn main() -> std::io::Result<()> {
         std::process::exit(0);
     }); 
 
+    if cmdline_opts.command == Command::TraceText { 
+        for arg in cmdline_opts.programs.iter() {
+            let file = std::fs::File::open(arg)?;
+            execute::trace::to_text(file, std::io::stdout().lock())?;
+        }
+        return Ok(());
+    }
+
+    if cmdline_opts.command == Command::Diff { 
+        return diff::run(&cmdline_opts, HEAPSIZE);
+    }
+
+    if cmdline_opts.command == Command::ProfileDiff { 
+        return profile::diff(&cmdline_opts);
+    }
+
+    if cmdline_opts.command == Command::Build { 
+        return build::run(&cmdline_opts, HEAPSIZE);
+    }
+
+    if cmdline_opts.command == Command::Transpile { 
+        return transpile::run(&cmdline_opts, HEAPSIZE);
+    }
+
+    let options = diff::parse_options(cmdline_opts.config.as_deref().unwrap_or(""), HEAPSIZE)?;
+
     /* Iterate through each BF file */
     for (itr, arg) in cmdline_opts.programs.iter().enumerate() {
-        let buffer = std::fs::read(&arg)?; 
+        let buffer = std::fs::read(arg)?; 
 
         /* Generate program and compile to bytecode */
-        let mut prog = match parser::new_program(&buffer, HEAPSIZE) { 
+        let mut prog = match parser::new_program(&buffer, &options) { 
             Ok(program) => program,
             Err(genbc_err) => { 
-                println!("Error compiling {} to byte code : {:#?}", arg, genbc_err); 
+                println!("Error compiling {} to byte code : {}", arg, genbc_err); 
                 continue; 
             }
         };
 
+        prog.set_fuel(cmdline_opts.fuel);
+        prog.jit_options = cmdline_opts.jit.clone();
+        prog.code_cache = cmdline_opts.code_cache.clone();
+        prog.perf = execute::perf::PerfOptions { 
+            map: cmdline_opts.perf_map,
+            jitdump: cmdline_opts.jitdump,
+            /* perf annotate opens the source by the name in the line numbers */
+            source: std::fs::canonicalize(arg).map_or_else(|_| arg.clone(), |path| path.display().to_string()),
+        };
+
         /* Execute using user selected execution engine */
-        if let Ok((ret, elapsed)) = match cmdline_opts.exec_engine { 
-            ExecutionEngine::Interpreter => prog.interpret(), 
-            ExecutionEngine::CraneLift => {
-                prog.jit_compile(cmdline_opts.clir).or_else(|x| Err("Jit Compilation Error"));
-                prog.jit_exec(cmdline_opts.clir).map_err(|x| "Jit execution error")
-            }, 
-        } { 
-            println!("\n============"); 
-            println!("prog[{}][{} <{:?}>] returned {}, elapsed-time = {:?}"
-                        , itr, arg, cmdline_opts.exec_engine, ret, elapsed); 
-            println!("================="); 
-
-            #[cfg(feature="profile")]
-            { 
-                println!("profile"); 
-                println!(" +: {}", prog.profile.arith); 
-                println!(" >: {}", prog.profile.mv); 
-                println!(" ,: {}", prog.profile.inp); 
-                println!(" .: {}", prog.profile.out); 
-                println!(" [: {}", prog.profile.jmp); 
-                println!(" ]: {}", prog.profile.ret); 
-                println!(" loops:");
-                let repr = |range: std::ops::Range<usize>| -> String { 
-                    prog.txt[range]
-                        .iter()
-                        .map( |x| match x { 
-                            execute::BFIsa::Incr(n) => { 
-                                if *n >= 128 { 
-                                    format!("-{}", n.wrapping_neg())
-                                } else { 
-                                    format!("+{}", n)
-                                }
-                            }, 
-                            execute::BFIsa::Mv(n) => { 
-                                if *n < 0 {
-                                    format!("<{}", -n)
-                                } else { 
-                                    format!(">{}", n) 
-                                }
-                            },
-                            execute::BFIsa::In => ",".to_string(),
-                            execute::BFIsa::Out => ".".to_string(),
-                            execute::BFIsa::Jmp(_) => "[".to_string(),
-                            execute::BFIsa::Ret(_) => "]".to_string(),
-                            execute::BFIsa::LoopSetZero => "x".to_string(),
-                            execute::BFIsa::LoopMvData(n) => {
-                                if *n < 0 { 
-                                    format!("+<{}", -n)
-                                } else {
-                                    format!("+>{}", n)
-                                }
-                            },
-                            execute::BFIsa::LoopMvPtr(n) => {
-                                if *n < 0 { 
-                                    format!("<<{}", -n)
-                                } else {
-                                    format!(">>{}", n)
-                                }
-
-                            },
-                        })
-                        .fold(String::new(), |a,b| a + &b)
-                };
+        let mut compile_time = None;
+        let result = match (&cmdline_opts.exec_engine, &cmdline_opts.trace, &cmdline_opts.coverage) { 
+            (_, Some(_), _) | (_, _, Some(_)) if cmdline_opts.profile => { 
+                println!("--profile cannot be combined with tracing or coverage"); 
+                continue;
+            },
+            (engine, trace, coverage) if cmdline_opts.tape_stats 
+                && (cmdline_opts.profile || trace.is_some() || coverage.is_some() || !matches!(engine, ExecutionEngine::Interpreter)) => { 
+                println!("--tape-stats only works with -e Interpreter, without profiling, tracing or coverage"); 
+                continue;
+            },
+            (ExecutionEngine::Interpreter, Some(trace), _) => { 
+                let trace = per_program(trace, itr, &cmdline_opts.programs);
+                let mut tracer = execute::trace::Tracer::new(std::fs::File::create(&trace)?, cmdline_opts.trace_filter.clone())?;
+                let result = prog.interpret_traced(&mut tracer);
+                println!("\n{} trace records written to {}", tracer.records, trace);
+                result
+            },
+            (ExecutionEngine::Interpreter, None, Some(coverage)) => { 
+                let coverage = per_program(coverage, itr, &cmdline_opts.programs);
+                let mut counts = execute::OpCounts::default();
+                let result = prog.interpret_counted(&mut counts);
 
-                let mut loops: Vec<_> = prog.profile
-                    .loops
-                    .into_iter()
-                    .map(|(range, count)| (repr(range), count))
-                    .collect(); 
-
-                loops.sort_by(|a,b| a.0.cmp(&b.0));
-                for idx in 1..loops.len() { 
-                    if loops[idx - 1].0 == loops[idx].0 { 
-                        loops[idx].1 += loops[idx-1].1;
-                        loops[idx-1].1 = 0; /* mark to remove */
-                    }
-                }
-                loops.retain(|x| x.1 > 0);
-                loops.sort_by_key(|x| x.1);
-                for (code, count) in loops.into_iter().rev().take(20) { 
-                    println!("{:10}: {}", count, code); 
+                /* report coverage of failed runs too */
+                let annotated = format!("{}.cov", coverage);
+                let lcov = format!("{}.info", coverage);
+                execute::coverage::write_annotated(std::fs::File::create(&annotated)?, arg, &buffer, &prog, &counts)?;
+                execute::coverage::write_lcov(std::fs::File::create(&lcov)?, arg, &prog, &counts)?;
+                let summary = execute::coverage::summary(&prog, &counts);
+                println!("\ncoverage: {}/{} lines, {}/{} branches, written to {} and {}", 
+                    summary.lines_hit, summary.lines, summary.branches_hit, summary.branches, annotated, lcov);
+                result
+            },
+            (ExecutionEngine::Interpreter, None, None) if cmdline_opts.tape_stats => { 
+                let mut stats = execute::heatmap::TapeStats::default();
+                let result = prog.interpret_tape_stats(&mut stats);
+
+                /* report failed runs too, e.g. a pointer leaving a bounded tape */
+                println!();
+                heatmap::print(&prog, &stats, options.heap_size);
+                result
+            },
+            (ExecutionEngine::Interpreter, None, None) if cmdline_opts.profile => prog.interpret_profiled(), 
+            (ExecutionEngine::Interpreter, None, None) => prog.interpret(), 
+            (ExecutionEngine::CraneLift, Some(_), _) | (ExecutionEngine::CraneLift, _, Some(_)) 
+            | (ExecutionEngine::Tiered, Some(_), _) | (ExecutionEngine::Tiered, _, Some(_)) => { 
+                println!("Tracing and coverage are only supported with -e Interpreter"); 
+                continue;
+            },
+            (ExecutionEngine::Tiered, None, None) if cmdline_opts.profile => { 
+                println!("--profile is only supported with -e Interpreter or -e CraneLift"); 
+                continue;
+            },
+            (ExecutionEngine::Tiered, None, None) => { 
+                let mut tiering = execute::Tiering::new(cmdline_opts.tier_threshold);
+                let result = prog.interpret_tiered(&mut tiering);
+                println!("\ntiered: {} loops compiled in {:?}", tiering.compiled.len(), tiering.compile_time);
+                result
+            },
+            (ExecutionEngine::CraneLift, None, None) if cmdline_opts.fuel.is_some() => { 
+                println!("--fuel only works with -e Interpreter or -e Tiered"); 
+                continue;
+            },
+            (ExecutionEngine::CraneLift, None, None) => {
+                let compile_start = std::time::Instant::now();
+                let compiled = match cmdline_opts.profile { 
+                    true => prog.jit_compile_profiled(cmdline_opts.clir),
+                    false => prog.jit_compile(cmdline_opts.clir),
+                };
+                compile_time = Some(compile_start.elapsed());
+                match compiled { 
+                    Ok(_) => prog.jit_exec(cmdline_opts.clir),
+                    Err(err) => jit_fallback(&mut prog, arg, err, &cmdline_opts),
                 }
+            }, 
+        };
+        let (ret, elapsed) = match result { 
+            Ok(result) => result,
+            Err(err) => { 
+                println!("\nError running {} : {}", arg, err);
+                continue;
+            },
+        };
+
+        println!("\n============"); 
+        println!("prog[{}][{} <{:?}>] returned {}, elapsed-time = {:?}"
+                    , itr, arg, cmdline_opts.exec_engine, ret, elapsed); 
+        if let (Some(stats), Some(compile_time)) = (prog.code_stats(), compile_time) { 
+            let cached = if stats.cached {" (loaded from the code cache)"} else {""};
+            println!("compile-time = {:?}{}, code-size = {} bytes", compile_time, cached, stats.code_size); 
+            if prog.function_stats().len() > 1 && !stats.cached { 
+                print_function_stats(&prog, cmdline_opts.top);
             }
-        }; 
+        }
+        println!("================="); 
+
+        if cmdline_opts.profile { 
+            profile::print(&prog, &buffer, cmdline_opts.top);
+        }
+        if let Some(prefix) = &cmdline_opts.profile_out { 
+            profile::export(&prog, arg, &per_program(prefix, itr, &cmdline_opts.programs))?;
+        }
     }
     Ok(())
 }
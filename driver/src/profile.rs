@@ -0,0 +1,187 @@
+use execute::profile::{LoopProfile, Trips};
+use execute::{BFIsa, ProgramState};
+
+fn repr(ops: &[BFIsa]) -> String { 
+    ops.iter()
+        .map( |x| match x { 
+            BFIsa::Incr(n) => { 
+                if *n >= 128 { 
+                    format!("-{}", n.wrapping_neg())
+                } else { 
+                    format!("+{}", n)
+                }
+            }, 
+            BFIsa::Mv(n) => { 
+                if *n < 0 {
+                    format!("<{}", -n)
+                } else { 
+                    format!(">{}", n) 
+                }
+            },
+            BFIsa::In => ",".to_string(),
+            BFIsa::Out => ".".to_string(),
+            BFIsa::Jmp(_) => "[".to_string(),
+            BFIsa::Ret(_) => "]".to_string(),
+            BFIsa::LoopSetZero => "x".to_string(),
+            BFIsa::LoopMvData(n) => {
+                if *n < 0 { 
+                    format!("+<{}", -n)
+                } else {
+                    format!("+>{}", n)
+                }
+            },
+            BFIsa::LoopMvPtr(n) => {
+                if *n < 0 { 
+                    format!("<<{}", -n)
+                } else {
+                    format!(">>{}", n)
+                }
+            },
+        })
+        .fold(String::new(), |a,b| a + &b)
+}
+
+fn shorten(text: String, len: usize) -> String { 
+    if text.chars().count() > len { 
+        format!("{}...", text.chars().take(len).collect::<String>())
+    } else { 
+        text
+    }
+}
+
+fn location(prog: &ProgramState, offset: usize) -> String { 
+    let (line, col) = prog.srcmap.line_col(offset);
+    format!("{}:{}", line, col)
+}
+
+/* non empty buckets as <iterations>:<entries>, e.g. "0:3 1:10 2-3:5" */
+fn histogram(trips: &Trips) -> String { 
+    trips.buckets.iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(bucket, count)| { 
+            let range = Trips::bucket_range(bucket);
+            if range.start() == range.end() { 
+                format!("{}:{}", range.start(), count)
+            } else { 
+                format!("{}-{}:{}", range.start(), range.end(), count)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn hint(trips: &Trips) -> String { 
+    match (trips.min, trips.max) { 
+        (_, 0) => "never entered".to_string(),
+        (_, 1) => "runs at most once, acts as an if".to_string(),
+        (min, max) if min == max && trips.count() > 1 => format!("always {} iterations, unroll", max),
+        _ => String::new(),
+    }
+}
+
+fn print_tree(prog: &ProgramState, loops: &[LoopProfile], depth: usize) { 
+    for lp in loops.iter().filter(|lp| lp.inclusive > 0) { 
+        println!("{:>10} {:>12} {:>12}  {}{} {}", lp.iterations, lp.inclusive, lp.exclusive, 
+            "  ".repeat(depth), location(prog, lp.src.start), shorten(repr(&prog.txt[lp.start..=lp.end]), 40)); 
+        print_tree(prog, &lp.children, depth + 1);
+    }
+}
+
+pub fn print(prog: &ProgramState, src: &[u8], top: usize) { 
+    let totals = prog.profile.totals(&prog.txt);
+    println!("profile"); 
+    println!(" +: {}", totals.arith); 
+    println!(" >: {}", totals.mv); 
+    println!(" ,: {}", totals.inp); 
+    println!(" .: {}", totals.out); 
+    println!(" [: {}", totals.jmp); 
+    println!(" ]: {}", totals.ret); 
+    println!(" x: {}", totals.loopsetz); 
+    println!(" +>: {}", totals.loopmvdata); 
+    println!(" >>: {}", totals.loopmvptr); 
+
+    println!(" hot loops:");
+    println!("{:>10} {:>10} {:>12} {:>12}  {:<10} source", "iterations", "entries", "inclusive", "exclusive", "line:col"); 
+    for lp in prog.profile.hot_loops(prog, top) { 
+        let text = src[lp.src.clone()].iter()
+            .filter(|x| b"+-<>[].,".contains(x))
+            .map(|x| *x as char)
+            .collect::<String>();
+        println!("{:>10} {:>10} {:>12} {:>12}  {:<10} {}", lp.iterations, lp.entries, lp.inclusive, lp.exclusive, 
+            location(prog, lp.src.start), shorten(text, 40)); 
+    }
+
+    println!(" loop trips:");
+    println!("{:>10} {:>8} {:>10} {:>8}  {:<10} {:<36} hint", "reached", "min", "mean", "max", "line:col", "histogram (iterations:entries)"); 
+    for lp in prog.profile.hot_loops(prog, top).iter().filter(|lp| lp.trips.count() > 0) { 
+        println!("{:>10} {:>8} {:>10.1} {:>8}  {:<10} {:<36} {}", lp.trips.count(), lp.trips.min, lp.trips.mean(), lp.trips.max, 
+            location(prog, lp.src.start), shorten(histogram(&lp.trips), 33), hint(&lp.trips)); 
+    }
+
+    println!(" hot ops:");
+    for op in prog.profile.hot_ops(prog, top) { 
+        println!("{:>10}  {:<10} {}", op.count, location(prog, op.span.start), repr(&[op.op])); 
+    }
+
+    println!(" loop tree:");
+    println!("{:>10} {:>12} {:>12}  line:col", "iterations", "inclusive", "exclusive"); 
+    print_tree(prog, &prog.profile.loop_tree(prog), 0);
+}
+
+/* <prefix>.callgrind, <prefix>.folded and <prefix>.json */
+pub fn export(prog: &ProgramState, path: &str, prefix: &str) -> std::io::Result<()> { 
+    let callgrind = format!("{}.callgrind", prefix);
+    let folded = format!("{}.folded", prefix);
+    let json = format!("{}.json", prefix);
+    prog.profile.write_callgrind(std::fs::File::create(&callgrind)?, path, prog)?;
+    prog.profile.write_folded(std::fs::File::create(&folded)?, path, prog)?;
+    prog.profile.write_json(std::fs::File::create(&json)?, path, prog)?;
+    println!("profile written to {}, {} and {}", callgrind, folded, json);
+    Ok(())
+}
+
+fn percent(a: u64, b: u64) -> String { 
+    match a { 
+        0 => "new".to_string(),
+        a => format!("{:+.1}%", (b as f64 - a as f64) * 100.0 / a as f64),
+    }
+}
+
+pub fn diff(cmdline: &super::parser::CmdLine) -> std::io::Result<()> { 
+    let [file_a, file_b] = &cmdline.programs[..] else { 
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "profile-diff expects two JSON profiles"));
+    };
+    let a = execute::profile::read_json(std::fs::File::open(file_a)?)?;
+    let b = execute::profile::read_json(std::fs::File::open(file_b)?)?;
+
+    println!("a: {} ({})", file_a, a.program);
+    println!("b: {} ({})", file_b, b.program);
+    println!("ops executed: {} -> {} ({})", a.ops, b.ops, percent(a.ops, b.ops));
+
+    let changes = execute::profile::diff_json(&a, &b);
+    if changes.is_empty() { 
+        println!("no loop counts changed");
+        return Ok(());
+    }
+    println!("{} of {} loops changed:", changes.len(), a.loops.len().max(b.loops.len()));
+    println!("{:>12} {:>12} {:>8} {:>10} {:>10}  loop", "inclusive a", "inclusive b", "change", "iters a", "iters b"); 
+    for change in changes.iter().take(cmdline.top) { 
+        let count = |lp: &Option<execute::profile::JsonLoop>, f: fn(&execute::profile::JsonLoop) -> u64| match lp { 
+            Some(lp) => f(lp).to_string(),
+            None => "-".to_string(),
+        };
+        let change_str = match (&change.a, &change.b) { 
+            (Some(a), Some(b)) => percent(a.inclusive, b.inclusive),
+            (None, _) => "only b".to_string(),
+            (_, None) => "only a".to_string(),
+        };
+        println!("{:>12} {:>12} {:>8} {:>10} {:>10}  {}", 
+            count(&change.a, |lp| lp.inclusive), count(&change.b, |lp| lp.inclusive), change_str,
+            count(&change.a, |lp| lp.iterations), count(&change.b, |lp| lp.iterations), change.path); 
+    }
+    if changes.len() > cmdline.top { 
+        println!("... {} more (see --top)", changes.len() - cmdline.top);
+    }
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use execute::transpile::Lang;
+use execute::ProgramState;
+use std::path::{Path, PathBuf};
+
+/* None for x86-64 assembly */
+fn lang(target: Option<&str>) -> std::io::Result<Option<Lang>> {
+    match target {
+        None | Some("c") => Ok(Some(Lang::C)),
+        Some("rust") => Ok(Some(Lang::Rust)),
+        Some("js") => Ok(Some(Lang::Js)),
+        Some("python") => Ok(Some(Lang::Python)),
+        Some("asm") => Ok(None),
+        Some(other) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                               format!("--target {} (expected c, rust, js, python or asm)", other))),
+    }
+}
+
+pub fn run(cmdline: &super::parser::CmdLine, heap_size: usize) -> std::io::Result<()> {
+    let options = super::diff::parse_options(cmdline.config.as_deref().unwrap_or(""), heap_size)?;
+    let lang = lang(cmdline.target.as_deref())?;
+
+    for (itr, arg) in cmdline.programs.iter().enumerate() {
+        let src = std::fs::read(arg)?;
+        let prog = match ProgramState::with_options(&src, &options) {
+            Ok(program) => program,
+            Err(err) => {
+                println!("Error compiling {} to byte code : {}", arg, err);
+                continue;
+            },
+        };
+
+        /* <output> or the program's file name with the language's extension, in the current directory */
+        let output = match &cmdline.output {
+            Some(output) => PathBuf::from(super::per_program(output, itr, &cmdline.programs)),
+            None => Path::new(Path::new(arg).file_stem().unwrap_or_default()).with_extension(lang.map_or("s", Lang::extension)),
+        };
+        let name = Path::new(arg).file_name().unwrap_or_default().to_string_lossy();
+        let source = match lang {
+            Some(lang) => execute::transpile::transpile(&prog, lang, &name, cmdline.spans),
+            None => execute::asm::asm(&prog, &name, cmdline.spans),
+        };
+        std::fs::write(&output, source)?;
+        println!("prog[{}][{}] emitted {}", itr, arg, output.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,59 @@
+/*
+ * Runtime for BF programs compiled ahead of time by `build`. The object file
+ * defines bf_main() and the zero filled tape bf_tape; this file provides the
+ * I/O callbacks bf_main() imports and the process entry point.
+ *
+ * Callbacks get the index of the op calling them, for error reporting in the
+ * JIT, and return NULL on success. bf_read() returns BF_EOF at the end of
+ * input and leaves the cell to the EOF policy compiled into bf_main().
+ */
+
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+
+#define BF_OUT_OF_BOUNDS ((void *)1)
+#define BF_EOF ((void *)2)
+
+extern uint8_t bf_tape[];
+extern void *bf_main(uint8_t *tape, void *env, size_t *ptr, uint64_t *counters);
+
+/* any other non NULL pointer marks an I/O error */
+static char bf_io_error;
+
+void *bf_write(void *env, uint8_t value, size_t idx)
+{
+    (void)env;
+    (void)idx;
+    return putchar(value) == EOF ? &bf_io_error : NULL;
+}
+
+void *bf_read(void *env, uint8_t *buf, size_t idx)
+{
+    (void)env;
+    (void)idx;
+    fflush(stdout);
+    int c = getchar();
+    if (c == EOF)
+        return ferror(stdin) ? &bf_io_error : BF_EOF;
+    *buf = (uint8_t)c;
+    return NULL;
+}
+
+int main(void)
+{
+    size_t ptr = 0;
+    void *err = bf_main(bf_tape, NULL, &ptr, NULL);
+    if (fflush(stdout) != 0 && err == NULL)
+        err = &bf_io_error;
+
+    if (err == BF_OUT_OF_BOUNDS) {
+        fprintf(stderr, "tape pointer out of bounds\n");
+        return 2;
+    }
+    if (err != NULL) {
+        perror("I/O error");
+        return 1;
+    }
+    return 0;
+}
@@ -0,0 +1,109 @@
+/*
+ * Ahead of time compilation. The program is lowered exactly as for the JIT
+ * into a relocatable object that exports bf_main() and a zero filled tape
+ * (bf_tape) sized by the options it was parsed with. Cell width, tape model
+ * and EOF policy are compiled into the code. The I/O callbacks and main()
+ * come from runtime/bf_runtime.c, which link() builds into a static archive
+ * and links with the object using the system C compiler, so executables do
+ * not depend on cranelift.
+ *
+ * CC and AR in the environment override the compiler and archiver.
+ *
+ * object_for() and disassemble() take any ISA from target_isa(), so objects
+ * and listings for other architectures can be made on the host. Only host
+ * objects can be linked here.
+ */
+
+use crate::{disasm::{self, CodeStats}, host_isa, jit, main_signature, Instrument, Error, ProgramState};
+use cranelift::codegen::{
+    control::ControlPlane,
+    ir::{ExtFuncData, ExternalName, UserFuncName},
+    isa::{OwnedTargetIsa, TargetIsa},
+    Context,
+};
+use cranelift_module::{default_libcall_names, DataDescription, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+
+pub const RUNTIME: &str = include_str!("../runtime/bf_runtime.c");
+pub const TAPE: &str = "bf_tape";
+
+/* Relocatable object for the host, name is recorded as the object's file name */
+pub fn object(prog: &ProgramState, name: &str) -> Result<Vec<u8>, Error> {
+    object_for(prog, name, host_isa(true)?)
+}
+
+/* Relocatable object for isa, in the object format of its triple */
+pub fn object_for(prog: &ProgramState, name: &str, isa: OwnedTargetIsa) -> Result<Vec<u8>, Error> {
+    let builder = ObjectBuilder::new(isa, name, default_libcall_names())
+        .map_err(|_| Error::Jit("Error creating object file"))?;
+    let mut module = ObjectModule::new(builder);
+    prog.lower(&mut module, jit::MAIN, 0..prog.txt.len(), &[], false, Instrument::default())?;
+
+    let tape = module.declare_data(TAPE, Linkage::Export, true, false)
+        .map_err(|_| Error::Jit("Error declaring the tape"))?;
+    let mut data = DataDescription::new();
+    data.define_zeroinit(prog.tape().len());
+    data.set_align(16);
+    module.define_data(tape, &data).map_err(|_| Error::Jit("Error defining the tape"))?;
+
+    module.finish().emit().map_err(|_| Error::Jit("Error writing object file"))
+}
+
+/* Listing of bf_main as compiled for isa with the ops and source each range of code came from, then its statistics */
+pub fn disassemble(prog: &ProgramState, isa: &dyn TargetIsa) -> Result<String, Error> {
+    let phase = Instant::now();
+    let (func, loops) = prog.build(main_signature(isa), UserFuncName::testcase(jit::MAIN), 0..prog.txt.len(), &[], Instrument::default(), &mut |func, name, sig| {
+        let signature = func.import_signature(sig);
+        Ok(func.import_function(ExtFuncData { name: ExternalName::testcase(name), signature, colocated: false }))
+    })?;
+    let (blocks, build) = (func.layout.blocks().count(), phase.elapsed());
+
+    let phase = Instant::now();
+    let mut ctx = Context::for_function(func);
+    if let Err(err) = ctx.compile(isa, &mut ControlPlane::default()) {
+        return Err(Error::Codegen { isa: isa.triple().to_string(), error: err.inner.to_string(), clif: ctx.func.display().to_string() });
+    }
+    let code = ctx.compiled_code().unwrap();
+    let mut stats = CodeStats { blocks, loops, build, compile: phase.elapsed(), ..Default::default() };
+    stats.measure(code, isa);
+
+    let mut out = format!("; {} for {}, {} bytes\n", jit::MAIN, isa.triple(), stats.code_size);
+    out.push_str(&disasm::listing(prog, isa, code, &|target| target.display(Some(&ctx.func.params))));
+    for line in stats.to_string().lines() {
+        out.push_str(&format!("; {}\n", line));
+    }
+    Ok(out)
+}
+
+fn run(cmd: &mut Command) -> io::Result<()> {
+    let status = cmd.status()
+        .map_err(|err| io::Error::new(err.kind(), format!("{:?}: {}", cmd.get_program(), err)))?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{:?} failed with {}", cmd, status)));
+    }
+    Ok(())
+}
+
+/* Link an object from object() with the runtime archive into an executable */
+pub fn link(object: &Path, exe: &Path) -> io::Result<()> {
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let ar = std::env::var("AR").unwrap_or_else(|_| "ar".to_string());
+    let dir = std::env::temp_dir().join(format!("bf-runtime-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+
+    let result = (|| {
+        let source = dir.join("bf_runtime.c");
+        let runtime = dir.join("bf_runtime.o");
+        let archive = dir.join("libbf_runtime.a");
+        std::fs::write(&source, RUNTIME)?;
+        run(Command::new(&cc).args(["-O2", "-c"]).arg(&source).arg("-o").arg(&runtime))?;
+        run(Command::new(&ar).arg("rcs").arg(&archive).arg(&runtime))?;
+        run(Command::new(&cc).arg(object).arg(&archive).arg("-o").arg(exe))
+    })();
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
@@ -0,0 +1,190 @@
+/*
+ * x86-64 assembly in GNU as (AT&T) syntax, generated op by op from fixed
+ * templates with no register allocation or scheduling, as a baseline next to
+ * what Cranelift makes of the same byte code. The output defines main() and
+ * uses putchar/getchar/fflush from libc, so `cc prog.s -o prog` builds it on
+ * Linux. Exit status as for aot::link() executables.
+ *
+ * %rbx holds the tape address and %r12 the pointer as a cell index, both
+ * callee saved so they survive the libc calls. Loops get labels named after
+ * the line and column of their '['; numbered local labels are only used
+ * inside a single op.
+ */
+
+use crate::{BFIsa, CellWidth, EofPolicy, ProgramState, TapeModel};
+use std::fmt::Write;
+
+struct Asm<'a> {
+    prog: &'a ProgramState,
+    cells: usize,
+    suffix: char,       /* b, w or l for cell sized instructions */
+    spans: bool,
+    out: String,
+}
+
+/* GNU as source for prog, name is only used in the header comment */
+pub fn asm(prog: &ProgramState, name: &str, spans: bool) -> String {
+    let mut asm = Asm {
+        prog,
+        cells: prog.heap.len() / prog.cell_width.bytes(),
+        suffix: match prog.cell_width {
+            CellWidth::W8 => 'b',
+            CellWidth::W16 => 'w',
+            CellWidth::W32 => 'l',
+        },
+        spans,
+        out: String::new(),
+    };
+
+    writeln!(asm.out, "# {}", crate::transpile::header(prog, name)).unwrap();
+    asm.out.push_str("\t.text\n\t.globl\tmain\n\t.type\tmain, @function\nmain:\n");
+    asm.out.push_str("\tpushq\t%rbx\n\tpushq\t%r12\n\tsubq\t$8, %rsp\t\t# keep the stack 16 byte aligned for calls\n");
+    asm.out.push_str("\tleaq\tbf_tape(%rip), %rbx\n\txorl\t%r12d, %r12d\n");
+    for idx in 0..prog.txt.len() {
+        asm.op(idx);
+    }
+    asm.out.push_str("\txorl\t%eax, %eax\n.Lexit:\n\taddq\t$8, %rsp\n\tpopq\t%r12\n\tpopq\t%rbx\n\tret\n");
+    if prog.tape == TapeModel::Bounded {
+        asm.out.push_str(".Lout_of_bounds:\n\txorl\t%edi, %edi\n\tcall\tfflush@PLT\n");
+        asm.out.push_str("\tmovl\t$2, %edi\n\tleaq\t.Loob_msg(%rip), %rsi\n\tmovl\t$27, %edx\n\tcall\twrite@PLT\n");
+        asm.out.push_str("\tmovl\t$2, %eax\n\tjmp\t.Lexit\n");
+    }
+    asm.out.push_str("\t.size\tmain, .-main\n\n");
+    if prog.tape == TapeModel::Bounded {
+        asm.out.push_str("\t.section\t.rodata\n.Loob_msg:\n\t.ascii\t\"tape pointer out of bounds\\n\"\n\n");
+    }
+    writeln!(asm.out, "\t.bss\n\t.p2align\t4\nbf_tape:\n\t.zero\t{}", prog.heap.len()).unwrap();
+    asm.out.push_str("\t.section\t.note.GNU-stack,\"\",@progbits\n");
+    asm.out
+}
+
+impl Asm<'_> {
+    fn cell(&self) -> String {
+        format!("(%rbx,%r12,{})", self.prog.cell_width.bytes())
+    }
+
+    /* the part of %eax a cell fits in */
+    fn acc(&self) -> &'static str {
+        match self.prog.cell_width {
+            CellWidth::W8 => "%al",
+            CellWidth::W16 => "%ax",
+            CellWidth::W32 => "%eax",
+        }
+    }
+
+    fn line(&mut self, ins: &str) {
+        writeln!(self.out, "\t{}", ins).unwrap();
+    }
+
+    fn label(&self, kind: &str, idx: usize) -> String {
+        let (line, col) = self.prog.srcmap.line_col(self.prog.spans[idx].start);
+        format!(".L{}_{}_{}", kind, line, col)
+    }
+
+    /* reg (a cell index) moved by disp cells; on a bounded tape leaving it jumps to .Lout_of_bounds */
+    fn offset(&mut self, reg: &str, disp: isize) {
+        match self.prog.tape {
+            TapeModel::Wrap => {
+                let disp = disp.rem_euclid(self.cells as isize);
+                self.line(&format!("addq\t${}, {}", disp, reg));
+                if self.cells.is_power_of_two() {
+                    self.line(&format!("andq\t${}, {}", self.cells - 1, reg));
+                } else {
+                    self.line(&format!("cmpq\t${}, {}", self.cells, reg));
+                    self.line("jb\t9f");
+                    self.line(&format!("subq\t${}, {}", self.cells, reg));
+                    self.out.push_str("9:\n");
+                }
+            },
+            TapeModel::Bounded => {
+                self.line(&format!("addq\t${}, {}", disp, reg));
+                self.line(&format!("cmpq\t${}, {}", self.cells, reg));
+                self.line("jae\t.Lout_of_bounds");
+            },
+        }
+    }
+
+    fn op(&mut self, idx: usize) {
+        let (cell, s) = (self.cell(), self.suffix);
+        let start = self.out.len();
+        let load = match self.prog.cell_width {
+            CellWidth::W8 => "movzbl",
+            CellWidth::W16 => "movzwl",
+            CellWidth::W32 => "movl",
+        };
+
+        match self.prog.txt[idx] {
+            BFIsa::Incr(rhs) => {
+                let rhs = match self.prog.cell_width {
+                    CellWidth::W8 if rhs > 128 => rhs as i64 - 256,
+                    CellWidth::W8 => rhs as i64,
+                    _ => rhs as i8 as i64,
+                };
+                match rhs >= 0 {
+                    true => self.line(&format!("add{}\t${}, {}", s, rhs, cell)),
+                    false => self.line(&format!("sub{}\t${}, {}", s, -rhs, cell)),
+                }
+            },
+            BFIsa::Out => {
+                self.line(&format!("{}\t{}, %edi", load, cell));
+                self.line("call\tputchar@PLT");
+            },
+            BFIsa::In => {
+                self.line("xorl\t%edi, %edi");
+                self.line("call\tfflush@PLT");
+                self.line("call\tgetchar@PLT");
+                self.line("cmpl\t$-1, %eax");
+                self.line("je\t1f");
+                self.line(&format!("mov{}\t{}, {}", s, self.acc(), cell));
+                match self.prog.eof {
+                    EofPolicy::Unchanged => self.out.push_str("1:\n"),
+                    eof => {
+                        self.line("jmp\t2f");
+                        self.out.push_str("1:\n");
+                        let value = if eof == EofPolicy::Zero { 0 } else { -1 };
+                        self.line(&format!("mov{}\t${}, {}", s, value, cell));
+                        self.out.push_str("2:\n");
+                    },
+                }
+            },
+            BFIsa::Mv(disp) if disp.rem_euclid(self.cells as isize) == 0 && (disp == 0 || self.prog.tape == TapeModel::Wrap) => (),
+            BFIsa::Mv(disp) => self.offset("%r12", disp),
+            BFIsa::LoopSetZero => self.line(&format!("mov{}\t$0, {}", s, cell)),
+            BFIsa::LoopMvData(disp) => {
+                self.line(&format!("{}\t{}, %eax", load, cell));
+                self.line("testl\t%eax, %eax");
+                self.line("jz\t3f");
+                self.line("movq\t%r12, %rcx");
+                self.offset("%rcx", disp);
+                self.line(&format!("add{}\t{}, (%rbx,%rcx,{})", s, self.acc(), self.prog.cell_width.bytes()));
+                self.line(&format!("mov{}\t$0, {}", s, cell));
+                self.out.push_str("3:\n");
+            },
+            BFIsa::LoopMvPtr(disp) => {
+                self.out.push_str("1:\n");
+                self.line(&format!("cmp{}\t$0, {}", s, cell));
+                self.line("je\t2f");
+                self.offset("%r12", disp);
+                self.line("jmp\t1b");
+                self.out.push_str("2:\n");
+            },
+            BFIsa::Jmp(_) => {
+                self.line(&format!("cmp{}\t$0, {}", s, cell));
+                self.line(&format!("je\t{}", self.label("end", idx)));
+                writeln!(self.out, "{}:", self.label("loop", idx)).unwrap();
+            },
+            BFIsa::Ret(start) => {
+                self.line(&format!("cmp{}\t$0, {}", s, cell));
+                self.line(&format!("jne\t{}", self.label("loop", start)));
+                writeln!(self.out, "{}:", self.label("end", start)).unwrap();
+            },
+        }
+
+        /* span comment on the first instruction of the op */
+        if self.spans && self.out.len() > start {
+            let (line, col) = self.prog.srcmap.line_col(self.prog.spans[idx].start);
+            let eol = start + self.out[start..].find('\n').unwrap();
+            self.out.insert_str(eol, &format!("\t\t# {}:{}", line, col));
+        }
+    }
+}
@@ -0,0 +1,320 @@
+/*
+ * On-disk cache of JIT compiled code
+ *
+ * A program compiled by jit_compile() is stored in the cache directory as
+ * <hash>.bfc, hash being the 64 bit FNV-1a hash of a key that holds the
+ * crate version, the version of the generated code (CODEGEN_VERSION), the
+ * Cranelift version, the host ISA with every Cranelift setting, the tape
+ * configuration, the instrumentation and the optimised byte code. A file
+ * starts with the 4 byte magic "BFJC" followed by a version byte (currently
+ * 2), then unsigned LEB128 varints:
+ *
+ *   key            length, then the key bytes, compared in full on load
+ *   functions      count, then for every function, bf_main first
+ *     name         length, then UTF-8 bytes
+ *     alignment    of the code in bytes
+ *     code         length, then the machine code before relocation
+ *     blocks, loops, frame slots
+ *     relocations  count, then for every relocation its offset in the code,
+ *                  kind (index in RELOCS), target name (length, bytes) and
+ *                  addend (two's complement)
+ *     ops          count, then for every op the offset its code starts at
+ *                  and its index in ProgramState::txt, for perf line numbers
+ *
+ * and ends with the FNV-1a hash of everything before it as 8 little endian
+ * bytes. An entry that fails any check on load is deleted and the program
+ * compiled again. Hits touch their file, and after a store the least
+ * recently used files are deleted until the directory fits in max_bytes.
+ */
+
+use crate::{disasm::CodeStats, jit, main_signature, perf::CodeOps, trace::{read_varint, write_varint}, Error};
+use cranelift::codegen::{binemit::Reloc, ir::Signature, CompiledCode};
+use cranelift_jit::JITModule;
+use cranelift_module::{FuncId, FuncOrDataId, Linkage, Module, ModuleReloc, ModuleRelocTarget};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+pub const MAGIC: &[u8; 4] = b"BFJC";
+pub const VERSION: u8 = 2;
+
+/* relocations the JIT backends emit, stored by index, and the bytes each patches */
+const RELOCS: [(Reloc, usize); 10] = [
+    (Reloc::Abs4, 4), (Reloc::Abs8, 8),
+    (Reloc::X86PCRel4, 4), (Reloc::X86CallPCRel4, 4), (Reloc::X86CallPLTRel4, 4), (Reloc::X86GOTPCRel4, 4),
+    (Reloc::Arm64Call, 4), (Reloc::S390xPCRel32Dbl, 4), (Reloc::S390xPLTRel32Dbl, 4), (Reloc::RiscvCallPlt, 8),
+];
+
+#[derive(Clone, Debug)]
+pub struct CodeCache {
+    pub dir: PathBuf,
+    pub max_bytes: u64,     /* total size of the entries kept */
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CachedFunction {
+    pub name: String,
+    pub alignment: u64,
+    pub code: Vec<u8>,
+    pub blocks: usize,
+    pub loops: usize,
+    pub frame_slots: usize,
+    pub relocs: Vec<CachedReloc>,
+    pub ops: CodeOps,
+}
+
+/* a ModuleReloc with its target by name, as ids differ between modules */
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CachedReloc {
+    pub offset: u32,
+    pub kind: Reloc,
+    pub target: String,
+    pub addend: i64,
+}
+
+impl CachedFunction {
+    /* The code of function name defined in module, None if a relocation cannot be stored */
+    pub(crate) fn capture(module: &JITModule, name: &str, code: &CompiledCode, relocs: &[ModuleReloc], stats: &CodeStats, ops: &[(u32, usize)]) -> Option<CachedFunction> {
+        let relocs = relocs.iter().map(|reloc| {
+            let ModuleRelocTarget::User { index, .. } = reloc.name else { return None };
+            RELOCS.iter().find(|(kind, _)| *kind == reloc.kind)?;
+            let target = module.declarations().get_function_decl(FuncId::from_u32(index)).name.clone()?;
+            Some(CachedReloc { offset: reloc.offset, kind: reloc.kind, target, addend: reloc.addend })
+        }).collect::<Option<_>>()?;
+        Some(CachedFunction {
+            name: name.to_string(), alignment: code.buffer.alignment as u64, code: code.code_buffer().to_vec(),
+            blocks: stats.blocks, loops: stats.loops, frame_slots: stats.frame_slots, relocs, ops: ops.to_vec(),
+        })
+    }
+
+    pub(crate) fn stats(&self) -> CodeStats {
+        CodeStats {
+            code_size: self.code.len(), blocks: self.blocks, loops: self.loops, frame_slots: self.frame_slots,
+            cached: true, ..Default::default()
+        }
+    }
+}
+
+/* Declare and define funcs in module, bf_main exported, returns their ids */
+pub(crate) fn define(module: &mut JITModule, funcs: &[CachedFunction]) -> Result<Vec<FuncId>, Error> {
+    let sig = main_signature(module.isa());
+    let mut ids = Vec::new();
+    for func in funcs {
+        let linkage = if func.name == jit::MAIN { Linkage::Export } else { Linkage::Local };
+        ids.push(module.declare_function(&func.name, linkage, &sig)
+            .map_err(|_| Error::Jit("Error declaring cached code"))?);
+    }
+
+    for (func, id) in funcs.iter().zip(&ids) {
+        let mut relocs = Vec::new();
+        for reloc in &func.relocs {
+            let target = match module.get_name(&reloc.target) {
+                Some(FuncOrDataId::Func(id)) => id,
+                /* a runtime callback, the JIT resolves imports by name and ignores their signature */
+                _ => module.declare_function(&reloc.target, Linkage::Import, &Signature::new(module.isa().default_call_conv()))
+                    .map_err(|_| Error::Jit("Error declaring a runtime function"))?,
+            };
+            relocs.push(ModuleReloc { offset: reloc.offset, kind: reloc.kind, name: ModuleRelocTarget::user(0, target.as_u32()), addend: reloc.addend });
+        }
+        module.define_function_bytes(*id, func.alignment, &func.code, &relocs)
+            .map_err(|_| Error::Jit("Error defining cached code"))?;
+    }
+    Ok(ids)
+}
+
+impl CodeCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> CodeCache {
+        CodeCache { dir: dir.into(), max_bytes }
+    }
+
+    /* $XDG_CACHE_HOME/bf-jit, or ~/.cache/bf-jit */
+    pub fn default_dir() -> Option<PathBuf> {
+        std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .map(|dir| dir.join("bf-jit"))
+    }
+
+    pub fn path(&self, key: &[u8]) -> PathBuf {
+        self.dir.join(format!("{:016x}.bfc", fnv1a(key)))
+    }
+
+    /* The functions of a program of txt_len ops stored under key, None if there are none or the entry is invalid, which deletes it */
+    pub(crate) fn load(&self, key: &[u8], txt_len: usize) -> Option<Vec<CachedFunction>> {
+        let path = self.path(key);
+        let bytes = fs::read(&path).ok()?;
+        match decode(&bytes, key, txt_len) {
+            Ok(funcs) => {
+                let _ = File::options().append(true).open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+                Some(funcs)
+            },
+            Err(_) => {
+                let _ = fs::remove_file(&path);
+                None
+            },
+        }
+    }
+
+    /* Store funcs under key, then evict the least recently used entries */
+    pub(crate) fn store(&self, key: &[u8], funcs: &[CachedFunction]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        /* renamed into place, so other processes never load half an entry */
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, encode(key, funcs)?)?;
+        if let Err(err) = fs::rename(&tmp, &path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+        self.evict()
+    }
+
+    /* Delete entries, oldest first, until the rest fit in max_bytes */
+    pub fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "bfc") {
+                /* another process may have evicted it already */
+                if let Ok(meta) = fs::metadata(&path) {
+                    entries.push((meta.modified()?, meta.len(), path));
+                }
+            }
+        }
+        entries.sort();
+
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            let _ = fs::remove_file(path);
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    write_varint(out, bytes.len() as u64)?;
+    out.write_all(bytes)
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = read_varint(input)?;
+    if len > input.len() as u64 {
+        return Err(invalid("truncated"));
+    }
+    let (bytes, rest) = input.split_at(len as usize);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn read_string(input: &mut &[u8]) -> io::Result<String> {
+    String::from_utf8(read_bytes(input)?.to_vec()).map_err(|_| invalid("name is not UTF-8"))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn encode(key: &[u8], funcs: &[CachedFunction]) -> io::Result<Vec<u8>> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    write_bytes(&mut out, key)?;
+    write_varint(&mut out, funcs.len() as u64)?;
+    for func in funcs {
+        write_bytes(&mut out, func.name.as_bytes())?;
+        write_varint(&mut out, func.alignment)?;
+        write_bytes(&mut out, &func.code)?;
+        for count in [func.blocks, func.loops, func.frame_slots] {
+            write_varint(&mut out, count as u64)?;
+        }
+        write_varint(&mut out, func.relocs.len() as u64)?;
+        for reloc in &func.relocs {
+            let kind = RELOCS.iter().position(|(kind, _)| *kind == reloc.kind).ok_or_else(|| invalid("relocation kind"))?;
+            write_varint(&mut out, reloc.offset as u64)?;
+            write_varint(&mut out, kind as u64)?;
+            write_bytes(&mut out, reloc.target.as_bytes())?;
+            write_varint(&mut out, reloc.addend as u64)?;
+        }
+        write_varint(&mut out, func.ops.len() as u64)?;
+        for (offset, idx) in &func.ops {
+            write_varint(&mut out, *offset as u64)?;
+            write_varint(&mut out, *idx as u64)?;
+        }
+    }
+    let hash = fnv1a(&out);
+    out.extend_from_slice(&hash.to_le_bytes());
+    Ok(out)
+}
+
+/* The functions in an entry stored under key for a program of txt_len ops, checked to be loadable */
+fn decode(bytes: &[u8], key: &[u8], txt_len: usize) -> io::Result<Vec<CachedFunction>> {
+    if bytes.len() < MAGIC.len() + 1 + 8 {
+        return Err(invalid("truncated"));
+    }
+    let (body, hash) = bytes.split_at(bytes.len() - 8);
+    if &body[..4] != MAGIC || body[4] != VERSION {
+        return Err(invalid("not a cache entry of this version"));
+    }
+    if fnv1a(body).to_le_bytes() != hash {
+        return Err(invalid("checksum mismatch"));
+    }
+    let mut input = &body[5..];
+    if read_bytes(&mut input)? != key {
+        return Err(invalid("stored under another key"));
+    }
+
+    let mut funcs = Vec::new();
+    for _ in 0..read_varint(&mut input)? {
+        let name = read_string(&mut input)?;
+        let alignment = read_varint(&mut input)?;
+        let code = read_bytes(&mut input)?.to_vec();
+        let blocks = read_varint(&mut input)? as usize;
+        let loops = read_varint(&mut input)? as usize;
+        let frame_slots = read_varint(&mut input)? as usize;
+        let mut relocs = Vec::new();
+        for _ in 0..read_varint(&mut input)? {
+            let offset = read_varint(&mut input)?;
+            let (kind, size) = *RELOCS.get(read_varint(&mut input)? as usize).ok_or_else(|| invalid("relocation kind"))?;
+            if offset + size as u64 > code.len() as u64 {
+                return Err(invalid("relocation outside the code"));
+            }
+            let target = read_string(&mut input)?;
+            let addend = read_varint(&mut input)? as i64;
+            relocs.push(CachedReloc { offset: offset as u32, kind, target, addend });
+        }
+        let mut ops = Vec::new();
+        for _ in 0..read_varint(&mut input)? {
+            let offset = read_varint(&mut input)?;
+            let idx = read_varint(&mut input)?;
+            if offset >= code.len() as u64 || idx >= txt_len as u64 {
+                return Err(invalid("op outside the code or program"));
+            }
+            ops.push((offset as u32, idx as usize));
+        }
+        if !alignment.is_power_of_two() {
+            return Err(invalid("alignment"));
+        }
+        funcs.push(CachedFunction { name, alignment, code, blocks, loops, frame_slots, relocs, ops });
+    }
+    if !input.is_empty() {
+        return Err(invalid("trailing bytes"));
+    }
+
+    /* bf_main first, names unique and calls to the functions of the entry or the runtime */
+    let known = |name: &str| funcs.iter().any(|func| func.name == name) || jit::RUNTIME.contains(&name);
+    if funcs.first().is_none_or(|func| func.name != jit::MAIN)
+        || funcs.iter().enumerate().any(|(no, func)| funcs[..no].iter().any(|other| other.name == func.name))
+        || funcs.iter().any(|func| func.relocs.iter().any(|reloc| !known(&reloc.target))) {
+        return Err(invalid("unresolved functions"));
+    }
+    Ok(funcs)
+}
@@ -0,0 +1,151 @@
+/*
+ * Source level coverage built from the per op counts of interpret_counted().
+ *
+ * Every loop ('[' and loops folded by the peephole pass) is reported as a
+ * branch with two outcomes: entered and skipped. Reports are written as an
+ * annotated copy of the source in the style of gcov, and as an lcov
+ * tracefile.
+ */
+
+use crate::{BFIsa, OpCounts, ProgramState};
+use std::io::{self, Write};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoopCoverage {
+    pub idx: usize,         /* IR index of the '[' or folded loop */
+    pub src: usize,         /* source offset of the '[' */
+    pub reached: u64,
+    pub entered: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub lines: usize,
+    pub lines_hit: usize,
+    pub branches: usize,
+    pub branches_hit: usize,
+}
+
+/* Execution count of every source byte, None for bytes that are not BF commands */
+pub fn byte_counts(prog: &ProgramState, counts: &OpCounts, src: &[u8]) -> Vec<Option<u64>> {
+    let mut bytes = vec![None; src.len()];
+    for (idx, span) in prog.spans.iter().enumerate() {
+        for pos in span.clone().filter(|pos| b"+-<>[].,".contains(&src[*pos])) {
+            bytes[pos] = Some(counts.exec[idx]);
+        }
+    }
+    bytes
+}
+
+/* Highest execution count of the ops on each line (index 0 is line 1), None for lines without ops */
+pub fn line_counts(prog: &ProgramState, counts: &OpCounts) -> Vec<Option<u64>> {
+    let mut lines = vec![None; prog.srcmap.lines()];
+    for (idx, span) in prog.spans.iter().enumerate() {
+        let first = prog.srcmap.line_col(span.start).0;
+        let last = prog.srcmap.line_col(span.end - 1).0;
+        for line in &mut lines[first - 1..last] {
+            *line = Some(line.unwrap_or(0).max(counts.exec[idx]));
+        }
+    }
+    lines
+}
+
+pub fn loops(prog: &ProgramState, counts: &OpCounts) -> Vec<LoopCoverage> {
+    prog.txt.iter()
+        .enumerate()
+        .filter(|(_, op)| matches!(op, BFIsa::Jmp(_) | BFIsa::LoopSetZero | BFIsa::LoopMvData(_) | BFIsa::LoopMvPtr(_)))
+        .map(|(idx, _)| LoopCoverage {
+            idx,
+            src: prog.spans[idx].start,
+            reached: counts.exec[idx],
+            entered: counts.nonzero[idx],
+        })
+        .collect()
+}
+
+pub fn summary(prog: &ProgramState, counts: &OpCounts) -> Summary {
+    let lines = line_counts(prog, counts);
+    let loops = loops(prog, counts);
+    Summary {
+        lines: lines.iter().flatten().count(),
+        lines_hit: lines.iter().flatten().filter(|count| **count > 0).count(),
+        branches: 2 * loops.len(),
+        branches_hit: loops.iter()
+            .map(|lp| (lp.entered > 0) as usize + (lp.reached > lp.entered) as usize)
+            .sum(),
+    }
+}
+
+/*
+ * gcov style listing: count:line:source, '#####' marks lines that never ran.
+ * A line that ran with commands that did not gets a row marking them with '#'.
+ */
+pub fn write_annotated<W: Write>(mut out: W, name: &str, src: &[u8], prog: &ProgramState, counts: &OpCounts) -> io::Result<()> {
+    let lines = line_counts(prog, counts);
+    let loops = loops(prog, counts);
+    let bytes = byte_counts(prog, counts, src);
+
+    writeln!(out, "{:>9}:{:>5}:Source:{}", "-", 0, name)?;
+    for (line, count) in lines.iter().enumerate().map(|(line, count)| (line + 1, count)) {
+        let hits = match count {
+            None => "-".to_string(),
+            Some(0) => "#####".to_string(),
+            Some(n) => n.to_string(),
+        };
+        let text = String::from_utf8_lossy(&src[prog.srcmap.line_range(line)]);
+        writeln!(out, "{:>9}:{:>5}:{}", hits, line, text)?;
+
+        let range = prog.srcmap.line_range(line);
+        if count.is_some_and(|count| count > 0) && bytes[range.clone()].contains(&Some(0)) {
+            /* one column per character, tabs kept so the marks line up */
+            let marks: String = range
+                .filter(|pos| src[*pos] & 0xc0 != 0x80)
+                .map(|pos| match (bytes[pos], src[pos]) {
+                    (Some(0), _) => '#',
+                    (_, b'\t') => '\t',
+                    _ => ' ',
+                })
+                .collect();
+            writeln!(out, "{:>9}:{:>5}:{}", "", line, marks.trim_end())?;
+        }
+
+        /* point at loops on this line that never ran their body */
+        for lp in loops.iter().filter(|lp| prog.srcmap.line_col(lp.src).0 == line && lp.entered == 0) {
+            let col = prog.srcmap.line_col(lp.src).1;
+            let why = if lp.reached == 0 {
+                "loop never reached".to_string()
+            } else {
+                format!("loop always skipped (reached {} times)", lp.reached)
+            };
+            writeln!(out, "{:>9}:{:>5}:{}^ {}", "!!!!!", line, " ".repeat(col - 1), why)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_lcov<W: Write>(mut out: W, path: &str, prog: &ProgramState, counts: &OpCounts) -> io::Result<()> {
+    let lines = line_counts(prog, counts);
+    let summary = summary(prog, counts);
+
+    writeln!(out, "TN:")?;
+    writeln!(out, "SF:{}", path)?;
+    for lp in loops(prog, counts) {
+        let line = prog.srcmap.line_col(lp.src).0;
+        let (entered, skipped) = match lp.reached {
+            0 => ("-".to_string(), "-".to_string()),
+            reached => (lp.entered.to_string(), (reached - lp.entered).to_string()),
+        };
+        writeln!(out, "BRDA:{},{},0,{}", line, lp.idx, entered)?;
+        writeln!(out, "BRDA:{},{},1,{}", line, lp.idx, skipped)?;
+    }
+    writeln!(out, "BRF:{}", summary.branches)?;
+    writeln!(out, "BRH:{}", summary.branches_hit)?;
+    for (line, count) in lines.iter().enumerate() {
+        if let Some(count) = count {
+            writeln!(out, "DA:{},{}", line + 1, count)?;
+        }
+    }
+    writeln!(out, "LF:{}", summary.lines)?;
+    writeln!(out, "LH:{}", summary.lines_hit)?;
+    writeln!(out, "end_of_record")
+}
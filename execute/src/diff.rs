@@ -0,0 +1,264 @@
+/*
+ * Runs one program and input under two configurations in lockstep and
+ * reports the first sync point where pointer, tape or output diverge.
+ *
+ * Sync points are loop tests ('[' and ']') and I/O ops. Only source
+ * positions that are sync points under both configurations are compared,
+ * so a loop folded by the peephole pass on one side is simply skipped.
+ */
+
+use crate::{BFIsa, CellWidth, Env, Error, Options, ProgramState};
+use std::collections::HashSet;
+use std::io::{Cursor, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Engine {
+    #[default]
+    Interpreter,
+    CraneLift,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub engine: Engine,
+    pub options: Options,
+}
+
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub idx: usize,         /* IR index of the op that reached the sync point */
+    pub src: usize,         /* source offset of that op */
+    pub ptr: usize,
+    pub tape: Vec<u64>,
+    pub out_len: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DivergenceKind {
+    Location,                               /* different sync points reached */
+    Pointer,
+    Output { pos: usize },
+    Tape { cell: usize, a: u64, b: u64 },
+    Finished,                               /* one side finished or failed before the other */
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SideState {
+    pub at: Option<Checkpoint>,             /* None if the side had already finished */
+    pub prev_idx: Option<usize>,            /* IR index of the last matching checkpoint */
+    pub finished: Option<Result<(), String>>,
+    pub output: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    pub kind: DivergenceKind,
+    pub a: SideState,
+    pub b: SideState,
+}
+
+#[derive(Clone, Debug)]
+pub struct DiffResult {
+    pub checkpoints: u64,                   /* matching checkpoints before the divergence */
+    pub divergence: Option<Divergence>,
+    pub truncated: bool,                    /* stopped at the checkpoint limit */
+}
+
+enum Event {
+    Sync(Checkpoint),
+    Finished(Result<(), String>, usize, Vec<u64>),
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn cells(tape: &[u8], width: CellWidth) -> Vec<u64> {
+    tape.chunks_exact(width.bytes())
+        .map(|cell| cell.iter().rev().fold(0u64, |acc, byte| (acc << 8) | *byte as u64))
+        .collect()
+}
+
+fn sync_points(prog: &ProgramState) -> HashSet<usize> {
+    prog.txt.iter()
+        .zip(prog.spans.iter())
+        .filter(|(op, _)| matches!(op, BFIsa::Jmp(_) | BFIsa::Ret(_) | BFIsa::In | BFIsa::Out))
+        .map(|(_, span)| span.start)
+        .collect()
+}
+
+/* Check that prog can run on cfg's engine and compile it for the JIT, failures here are not divergences */
+fn prepare(prog: &mut ProgramState, cfg: &Config) -> Result<(), Error> {
+    match cfg.engine {
+        Engine::Interpreter => prog.check_interpretable(),
+        Engine::CraneLift => prog.jit_compile_synced(false).map(|_| ()),
+    }
+}
+
+fn run_side(mut prog: ProgramState, cfg: &Config, input: &[u8], points: Arc<HashSet<usize>>, out: SharedBuf, tx: SyncSender<Event>) -> ProgramState {
+    let spans = prog.spans.clone();
+    let width = cfg.options.cell_width;
+    let hook_tx = tx.clone();
+    let hook_out = out.clone();
+
+    prog.env = Env {
+        input: Box::new(Cursor::new(input.to_vec())),
+        output: Box::new(out),
+        sync: Some(Box::new(move |idx, ptr, tape| {
+            let src = spans[idx].start;
+            if !points.contains(&src) {
+                return true;
+            }
+            let out_len = hook_out.0.lock().unwrap().len();
+            hook_tx.send(Event::Sync(Checkpoint { idx, src, ptr, tape: cells(tape, width), out_len })).is_ok()
+        })),
+    };
+
+    let result = match cfg.engine {
+        Engine::Interpreter => prog.interpret_synced().map(|_| ()).map_err(|err| err.to_string()),
+        Engine::CraneLift => prog.jit_exec(false).map(|_| ()).map_err(|err| err.to_string()),
+    };
+    let _ = tx.send(Event::Finished(result, prog.ptr(), cells(prog.tape(), width)));
+    prog
+}
+
+fn compare(a: &Checkpoint, b: &Checkpoint, out_a: &[u8], out_b: &[u8], from: usize) -> Option<DivergenceKind> {
+    if a.src != b.src {
+        return Some(DivergenceKind::Location);
+    }
+    if a.ptr != b.ptr {
+        return Some(DivergenceKind::Pointer);
+    }
+    if let Some(pos) = first_output_diff(out_a, out_b, from) {
+        return Some(DivergenceKind::Output { pos });
+    }
+    first_tape_diff(&a.tape, &b.tape)
+}
+
+fn first_output_diff(out_a: &[u8], out_b: &[u8], from: usize) -> Option<usize> {
+    let common = out_a.len().min(out_b.len());
+    (from..common)
+        .find(|pos| out_a[*pos] != out_b[*pos])
+        .or((out_a.len() != out_b.len()).then_some(common))
+}
+
+fn first_tape_diff(a: &[u64], b: &[u64]) -> Option<DivergenceKind> {
+    (0..a.len().max(b.len()))
+        .map(|cell| (cell, a.get(cell).copied().unwrap_or(0), b.get(cell).copied().unwrap_or(0)))
+        .find(|(_, a, b)| a != b)
+        .map(|(cell, a, b)| DivergenceKind::Tape { cell, a, b })
+}
+
+/*
+ * Run src under configurations a and b, feeding both the same input. Stops after limit matching
+ * checkpoints. Returns the programs each side ran, for their source map and byte code
+ */
+pub fn diff(src: &[u8], input: &[u8], a: &Config, b: &Config, limit: Option<u64>) -> Result<(DiffResult, ProgramState, ProgramState), Error> {
+    let mut prog_a = ProgramState::with_options(src, &a.options)?;
+    let mut prog_b = ProgramState::with_options(src, &b.options)?;
+    prepare(&mut prog_a, a)?;
+    prepare(&mut prog_b, b)?;
+    let points: HashSet<usize> = sync_points(&prog_a)
+        .intersection(&sync_points(&prog_b))
+        .copied()
+        .collect();
+    let points = Arc::new(points);
+
+    let (out_a, out_b) = (SharedBuf::default(), SharedBuf::default());
+    let (tx_a, rx_a) = sync_channel(64);
+    let (tx_b, rx_b) = sync_channel(64);
+
+    std::thread::scope(|scope| {
+        let (pa, oa) = (points.clone(), out_a.clone());
+        let side_a = scope.spawn(move || run_side(prog_a, a, input, pa, oa, tx_a));
+        let (pb, ob) = (points.clone(), out_b.clone());
+        let side_b = scope.spawn(move || run_side(prog_b, b, input, pb, ob, tx_b));
+
+        /* dropping the receivers on return stops both sides at their next sync point */
+        let result = lockstep(rx_a, rx_b, &out_a, &out_b, limit)?;
+        Ok((result, side_a.join().unwrap(), side_b.join().unwrap()))
+    })
+}
+
+fn lockstep(rx_a: Receiver<Event>, rx_b: Receiver<Event>, out_a: &SharedBuf, out_b: &SharedBuf, limit: Option<u64>) -> Result<DiffResult, Error> {
+    let mut checkpoints = 0u64;
+    let mut prev_idx = (None, None);
+    let mut out_checked = 0;
+    let lost = || Event::Finished(Err("side terminated unexpectedly".to_string()), 0, Vec::new());
+
+    loop {
+        if limit.is_some_and(|limit| checkpoints >= limit) {
+            return Ok(DiffResult { checkpoints, divergence: None, truncated: true });
+        }
+
+        let ev_a = rx_a.recv().unwrap_or_else(|_| lost());
+        let ev_b = rx_b.recv().unwrap_or_else(|_| lost());
+        let output_a = out_a.0.lock().unwrap();
+        let output_b = out_b.0.lock().unwrap();
+
+        let side = |at: Option<Checkpoint>, prev: Option<usize>, finished: Option<Result<(), String>>, output: &[u8]| SideState {
+            at, prev_idx: prev, finished, output: output.to_vec()
+        };
+
+        let divergence = match (ev_a, ev_b) {
+            (Event::Sync(a), Event::Sync(b)) => {
+                let (len_a, len_b) = (a.out_len, b.out_len);
+                match compare(&a, &b, &output_a[..len_a], &output_b[..len_b], out_checked) {
+                    None => {
+                        checkpoints += 1;
+                        prev_idx = (Some(a.idx), Some(b.idx));
+                        out_checked = len_a;
+                        continue;
+                    },
+                    Some(kind) => Divergence {
+                        kind,
+                        a: side(Some(a), prev_idx.0, None, &output_a[..len_a]),
+                        b: side(Some(b), prev_idx.1, None, &output_b[..len_b]),
+                    },
+                }
+            },
+            (Event::Finished(res_a, ptr_a, tape_a), Event::Finished(res_b, ptr_b, tape_b)) => {
+                let kind = if res_a != res_b {
+                    Some(DivergenceKind::Finished)
+                } else if ptr_a != ptr_b {
+                    Some(DivergenceKind::Pointer)
+                } else if let Some(pos) = first_output_diff(&output_a, &output_b, out_checked) {
+                    Some(DivergenceKind::Output { pos })
+                } else {
+                    first_tape_diff(&tape_a, &tape_b)
+                };
+                match kind {
+                    None => return Ok(DiffResult { checkpoints, divergence: None, truncated: false }),
+                    Some(kind) => Divergence {
+                        kind,
+                        a: side(None, prev_idx.0, Some(res_a), &output_a),
+                        b: side(None, prev_idx.1, Some(res_b), &output_b),
+                    },
+                }
+            },
+            (Event::Sync(a), Event::Finished(res_b, ..)) => Divergence {
+                kind: DivergenceKind::Finished,
+                a: side(Some(a.clone()), prev_idx.0, None, &output_a[..a.out_len]),
+                b: side(None, prev_idx.1, Some(res_b), &output_b),
+            },
+            (Event::Finished(res_a, ..), Event::Sync(b)) => Divergence {
+                kind: DivergenceKind::Finished,
+                a: side(None, prev_idx.0, Some(res_a), &output_a),
+                b: side(Some(b.clone()), prev_idx.1, None, &output_b[..b.out_len]),
+            },
+        };
+        return Ok(DiffResult { checkpoints, divergence: Some(divergence), truncated: false });
+    }
+}
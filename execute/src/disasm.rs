@@ -0,0 +1,100 @@
+/*
+ * Listings and statistics of compiled code. build() tags the instructions
+ * of every op with the op's index as their source location, so the machine
+ * code can be split into the ranges each op produced and headed by the op
+ * and its line:col in the BF source. Code outside any op is the prologue,
+ * the epilogue, the shared exit path and whatever register moves and hoisted
+ * constants Cranelift placed between ops.
+ */
+
+use crate::ProgramState;
+use cranelift::codegen::{isa::TargetIsa, CompiledCode, FinalizedRelocTarget};
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default)]
+pub struct CodeStats {
+    pub code_size: usize,       /* bytes of machine code */
+    pub blocks: usize,          /* CLIF blocks */
+    pub loops: usize,           /* '[' loops that were not folded */
+    pub frame_slots: usize,     /* pointer sized slots of the stack frame, register spills and saved callee-saved registers alike */
+    pub build: Duration,        /* byte code to CLIF */
+    pub verify: Duration,
+    pub compile: Duration,      /* CLIF to machine code */
+    pub cached: bool,           /* loaded from the code cache, nothing was compiled */
+}
+
+impl CodeStats {
+    /* Size and frame slots of the code compiled for isa, Cranelift does not report spills apart from saved registers */
+    pub(crate) fn measure(&mut self, code: &CompiledCode, isa: &dyn TargetIsa) {
+        self.code_size = code.buffer.data().len();
+        self.frame_slots = code.frame_size as usize / isa.pointer_bytes() as usize;
+    }
+
+    pub(crate) fn add(&mut self, other: &CodeStats) {
+        self.code_size += other.code_size;
+        self.blocks += other.blocks;
+        self.loops += other.loops;
+        self.frame_slots += other.frame_slots;
+        self.build += other.build;
+        self.verify += other.verify;
+        self.compile += other.compile;
+    }
+
+    pub fn compile_time(&self) -> Duration {
+        self.build + self.verify + self.compile
+    }
+}
+
+impl fmt::Display for CodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "code size    {} bytes", self.code_size)?;
+        writeln!(f, "blocks       {}", self.blocks)?;
+        writeln!(f, "loops        {}", self.loops)?;
+        writeln!(f, "frame slots  {}", self.frame_slots)?;
+        match self.cached {
+            true => write!(f, "compile time none, loaded from the code cache"),
+            false => write!(f, "compile time {:?} (build {:?}, verify {:?}, cranelift {:?})",
+                self.compile_time(), self.build, self.verify, self.compile),
+        }
+    }
+}
+
+/* Disassembly of code compiled for isa, call targets are named by name() */
+pub(crate) fn listing(prog: &ProgramState, isa: &dyn TargetIsa, code: &CompiledCode, name: &dyn Fn(&FinalizedRelocTarget) -> String) -> String {
+    let mut out = String::new();
+    let cs = match isa.to_capstone() {
+        Ok(cs) => cs,
+        Err(err) => return format!("; no disassembler for {} : {}\n", isa.triple(), err),
+    };
+    let insns = match cs.disasm_all(code.buffer.data(), 0) {
+        Ok(insns) => insns,
+        Err(err) => return format!("; disassembly failed : {}\n", err),
+    };
+
+    let srclocs = code.buffer.get_srclocs_sorted();
+    let mut op = None;
+    for insn in insns.iter() {
+        let (start, end) = (insn.address() as u32, insn.address() as u32 + insn.bytes().len() as u32);
+        let idx = srclocs.iter()
+            .find(|loc| loc.start <= start && start < loc.end && !loc.loc.is_default())
+            .map(|loc| loc.loc.bits() as usize);
+        if op != Some(idx) {
+            match idx {
+                Some(idx) => {
+                    let (line, col) = prog.srcmap.line_col(prog.spans[idx].start);
+                    writeln!(out, "; {}:{} IR #{} {:?}", line, col, idx, prog.txt[idx]).unwrap();
+                },
+                None => out.push_str("; no op (prologue, epilogue, exit or moves)\n"),
+            }
+            op = Some(idx);
+        }
+
+        write!(out, "  {:6x}:  {} {}", start, insn.mnemonic().unwrap_or(""), insn.op_str().unwrap_or("")).unwrap();
+        for reloc in code.buffer.relocs().iter().filter(|reloc| (start..end).contains(&reloc.offset)) {
+            write!(out, "  ; {}", name(&reloc.target)).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,185 @@
+/*
+ * Static ELF64 executables for x86-64 Linux, written without a linker. The
+ * program is compiled exactly as for aot::object() and placed after a small
+ * hand written entry stub and raw read/write syscall shims standing in for
+ * the runtime callbacks; the tape is a .bss style segment. There is no libc,
+ * no section table and nothing time or path dependent in the output, so the
+ * same program and options always give the same bytes.
+ *
+ * Exit status matches aot::link() executables: 0, 1 on an I/O error and 2
+ * when the pointer leaves a bounded tape. Output is unbuffered.
+ */
+
+use crate::{host_isa, main_signature, Instrument, Error, ProgramState};
+use cranelift::codegen::{
+    binemit::Reloc,
+    control::ControlPlane,
+    ir::{ExtFuncData, ExternalName, UserExternalName, UserFuncName},
+    Context, FinalizedRelocTarget,
+};
+use target_lexicon::{Architecture, OperatingSystem};
+
+const BASE: u64 = 0x400000;
+const PAGE: u64 = 0x1000;
+const EHDR: usize = 64;
+const PHDR: usize = 56;
+const PHNUM: usize = 3;
+
+/*
+ * _start: bf_main(tape, NULL, &ptr, NULL) with ptr on the stack, then exit
+ * with 0, 2 or 1 for NULL, out of bounds and anything else.
+ */
+const START: [u8; 64] = [
+    0x48, 0x83, 0xec, 0x10,                     // sub rsp,16
+    0x48, 0xc7, 0x04, 0x24, 0x00, 0x00, 0x00, 0x00, // mov qword [rsp],0
+    0x48, 0xbf, 0, 0, 0, 0, 0, 0, 0, 0,         // movabs rdi,<tape>
+    0x31, 0xf6,                                 // xor esi,esi
+    0x48, 0x89, 0xe2,                           // mov rdx,rsp
+    0x31, 0xc9,                                 // xor ecx,ecx
+    0xe8, 0, 0, 0, 0,                           // call bf_main
+    0x31, 0xff,                                 // xor edi,edi
+    0x48, 0x85, 0xc0,                           // test rax,rax
+    0x74, 0x10,                                 // je exit
+    0xbf, 0x02, 0x00, 0x00, 0x00,               // mov edi,2
+    0x48, 0x83, 0xf8, 0x01,                     // cmp rax,1
+    0x74, 0x05,                                 // je exit
+    0xbf, 0x01, 0x00, 0x00, 0x00,               // mov edi,1
+    0xb8, 0x3c, 0x00, 0x00, 0x00,               // exit: mov eax,60
+    0x0f, 0x05,                                 // syscall
+];
+const START_TAPE: usize = 14;   /* imm64 of the movabs */
+const START_CALL: usize = 30;   /* rel32 of the call */
+
+/* bf_write(env, value, idx): write(1, &value, 1), returns NULL or 3 */
+const WRITE: [u8; 44] = [
+    0x40, 0x88, 0x74, 0x24, 0xf8,               // mov [rsp-8],sil
+    0xb8, 0x01, 0x00, 0x00, 0x00,               // mov eax,1
+    0xbf, 0x01, 0x00, 0x00, 0x00,               // mov edi,1
+    0x48, 0x8d, 0x74, 0x24, 0xf8,               // lea rsi,[rsp-8]
+    0xba, 0x01, 0x00, 0x00, 0x00,               // mov edx,1
+    0x0f, 0x05,                                 // syscall
+    0x48, 0x83, 0xf8, 0x01,                     // cmp rax,1
+    0xb8, 0x00, 0x00, 0x00, 0x00,               // mov eax,0
+    0x74, 0x05,                                 // je done
+    0xb8, 0x03, 0x00, 0x00, 0x00,               // mov eax,3
+    0xc3,                                       // done: ret
+];
+
+/* bf_read(env, buf, idx): read(0, buf, 1), returns NULL, JIT_EOF or 3 */
+const READ: [u8; 36] = [
+    0x31, 0xc0,                                 // xor eax,eax
+    0x31, 0xff,                                 // xor edi,edi
+    0xba, 0x01, 0x00, 0x00, 0x00,               // mov edx,1
+    0x0f, 0x05,                                 // syscall
+    0x48, 0x83, 0xf8, 0x01,                     // cmp rax,1
+    0x74, 0x10,                                 // je ok
+    0x48, 0x85, 0xc0,                           // test rax,rax
+    0xb8, 0x02, 0x00, 0x00, 0x00,               // mov eax,2
+    0x74, 0x05,                                 // je done
+    0xb8, 0x03, 0x00, 0x00, 0x00,               // mov eax,3
+    0xc3,                                       // done: ret
+    0x31, 0xc0,                                 // ok: xor eax,eax
+    0xc3,                                       // ret
+];
+
+/* runtime callbacks bf_main may import, by index in the external names of the function */
+const SHIMS: [(&str, &[u8]); 2] = [("bf_write", &WRITE), ("bf_read", &READ)];
+
+fn align(value: usize, to: usize) -> usize {
+    value.div_ceil(to) * to
+}
+
+/* A complete executable for prog, the tape model, cell width and EOF policy are compiled in */
+pub fn executable(prog: &ProgramState) -> Result<Vec<u8>, Error> {
+    let isa = host_isa(false)?;
+    if isa.triple().architecture != Architecture::X86_64 || isa.triple().operating_system != OperatingSystem::Linux {
+        return Err(Error::Unsupported("ELF executables are only supported on x86-64 Linux"));
+    }
+
+    let (func, _) = prog.build(main_signature(&*isa), UserFuncName::user(0, 0), 0..prog.txt.len(), &[], Instrument::default(), &mut |func, name, sig| {
+        let index = SHIMS.iter().position(|(shim, _)| *shim == name)
+            .ok_or(Error::Unsupported("Runtime function not available in ELF executables"))?;
+        let signature = func.import_signature(sig);
+        let name = func.declare_imported_user_function(UserExternalName::new(1, index as u32));
+        Ok(func.import_function(ExtFuncData { name: ExternalName::user(name), signature, colocated: true }))
+    })?;
+    let mut ctx = Context::for_function(func);
+    if let Err(err) = ctx.compile(&*isa, &mut ControlPlane::default()) { 
+        let error = err.inner.to_string();
+        return Err(Error::Codegen { isa: isa.triple().to_string(), error, clif: ctx.func.display().to_string() });
+    }
+    let code = ctx.compiled_code().unwrap();
+
+    /* headers, _start, shims, then bf_main, all in one read/execute segment from BASE */
+    let start = align(EHDR + PHNUM * PHDR, 16);
+    let mut shims = Vec::new();
+    let mut shim_at = start + START.len();
+    for (_, shim) in SHIMS.iter() {
+        shims.push(shim_at);
+        shim_at += shim.len();
+    }
+    let main = align(shim_at, 16);
+    let text_len = main + code.buffer.data().len();
+    let tape = BASE + align(text_len, PAGE as usize) as u64;
+    let addr = |offset: usize| BASE + offset as u64;
+
+    let mut out = vec![0u8; main];
+    out.extend_from_slice(code.buffer.data());
+    let mut stub = START;
+    stub[START_TAPE..START_TAPE + 8].copy_from_slice(&tape.to_le_bytes());
+    let call = addr(main) as i64 - addr(start + START_CALL + 4) as i64;
+    stub[START_CALL..START_CALL + 4].copy_from_slice(&(call as i32).to_le_bytes());
+    out[start..start + START.len()].copy_from_slice(&stub);
+    for ((_, shim), at) in SHIMS.iter().zip(shims.iter()) {
+        out[*at..*at + shim.len()].copy_from_slice(shim);
+    }
+
+    for reloc in code.buffer.relocs() {
+        let target = match &reloc.target {
+            FinalizedRelocTarget::ExternalName(ExternalName::User(name)) =>
+                shims[ctx.func.params.user_named_funcs()[*name].index as usize],
+            _ => return Err(Error::Jit("Unsupported relocation in compiled code")),
+        };
+        let at = main + reloc.offset as usize;
+        let value = addr(target) as i64 + reloc.addend;
+        match reloc.kind {
+            Reloc::X86CallPCRel4 | Reloc::X86PCRel4 =>
+                out[at..at + 4].copy_from_slice(&((value - addr(at) as i64) as i32).to_le_bytes()),
+            Reloc::Abs8 => out[at..at + 8].copy_from_slice(&value.to_le_bytes()),
+            _ => return Err(Error::Jit("Unsupported relocation in compiled code")),
+        }
+    }
+
+    /* ELF header */
+    let mut header = Vec::with_capacity(start);
+    header.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    header.extend_from_slice(&2u16.to_le_bytes());                      // ET_EXEC
+    header.extend_from_slice(&0x3eu16.to_le_bytes());                   // EM_X86_64
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&addr(start).to_le_bytes());               // entry
+    header.extend_from_slice(&(EHDR as u64).to_le_bytes());             // program headers
+    header.extend_from_slice(&0u64.to_le_bytes());                      // no section headers
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(EHDR as u16).to_le_bytes());
+    header.extend_from_slice(&(PHDR as u16).to_le_bytes());
+    header.extend_from_slice(&(PHNUM as u16).to_le_bytes());
+    header.extend_from_slice(&[0; 6]);
+
+    /* program headers: text, tape and a non executable stack */
+    let mut phdr = |kind: u32, flags: u32, vaddr: u64, filesz: usize, memsz: usize| {
+        header.extend_from_slice(&kind.to_le_bytes());
+        header.extend_from_slice(&flags.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&vaddr.to_le_bytes());
+        header.extend_from_slice(&vaddr.to_le_bytes());
+        header.extend_from_slice(&(filesz as u64).to_le_bytes());
+        header.extend_from_slice(&(memsz as u64).to_le_bytes());
+        header.extend_from_slice(&(if kind == 1 { PAGE } else { 16 }).to_le_bytes());
+    };
+    phdr(1, 5, BASE, text_len, text_len);                              // PT_LOAD, R+X
+    phdr(1, 6, tape, 0, prog.tape().len());                             // PT_LOAD, R+W
+    phdr(0x6474e551, 6, 0, 0, 0);                                       // PT_GNU_STACK
+    out[..header.len()].copy_from_slice(&header);
+
+    Ok(out)
+}
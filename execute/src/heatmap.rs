@@ -0,0 +1,121 @@
+/*
+ * Tape usage: per cell read/write counts recorded by interpret_tape_stats(),
+ * how far the pointer moved from where it started, and a static estimate of
+ * the same range for programs whose loops all leave the pointer where they
+ * found it.
+ *
+ * Pointer positions are relative to the starting cell and do not wrap, so a
+ * program that walks left of its first cell shows up as a negative lowest.
+ */
+
+use crate::BFIsa;
+use std::ops::{Range, RangeInclusive};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TapeStats {
+    pub reads: Vec<u64>,        /* indexed by tape cell */
+    pub writes: Vec<u64>,
+    pub pos: isize,             /* pointer relative to the start */
+    pub lowest: isize,
+    pub highest: isize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bucket {
+    pub cells: Range<usize>,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl TapeStats {
+    pub fn new(cells: usize) -> Self {
+        TapeStats { reads: vec![0; cells], writes: vec![0; cells], ..Default::default() }
+    }
+
+    pub(crate) fn read(&mut self, cell: usize) {
+        self.reads[cell] += 1;
+    }
+
+    pub(crate) fn write(&mut self, cell: usize) {
+        self.writes[cell] += 1;
+    }
+
+    pub(crate) fn moved(&mut self, disp: isize) {
+        self.pos += disp;
+        self.reach(0);
+    }
+
+    /* the cell disp away from the pointer was accessed */
+    pub(crate) fn reach(&mut self, disp: isize) {
+        self.lowest = self.lowest.min(self.pos + disp);
+        self.highest = self.highest.max(self.pos + disp);
+    }
+
+    pub fn touched(&self) -> usize {
+        self.reads.iter().zip(self.writes.iter()).filter(|(r, w)| **r + **w > 0).count()
+    }
+
+    /* cells between the lowest and highest pointer position */
+    pub fn span(&self) -> usize {
+        (self.highest - self.lowest + 1) as usize
+    }
+
+    /* smallest power of two holding every cell the pointer reached */
+    pub fn suggested_size(&self) -> usize {
+        self.span().next_power_of_two()
+    }
+
+    /* Access counts of the tape cells from the first to the last one touched, in at most width buckets */
+    pub fn heatmap(&self, width: usize) -> Vec<Bucket> {
+        let touched = |cell: &usize| self.reads[*cell] + self.writes[*cell] > 0;
+        let (first, last) = match ((0..self.reads.len()).find(touched), (0..self.reads.len()).rfind(touched)) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Vec::new(),
+        };
+
+        let per_bucket = (last - first + 1).div_ceil(width.max(1));
+        (first..=last).step_by(per_bucket)
+            .map(|start| {
+                let cells = start..(start + per_bucket).min(last + 1);
+                Bucket {
+                    reads: self.reads[cells.clone()].iter().sum(),
+                    writes: self.writes[cells.clone()].iter().sum(),
+                    cells,
+                }
+            })
+            .collect()
+    }
+}
+
+/*
+ * Cells the program can reach relative to its start, found without running
+ * it. Only possible when every loop body leaves the pointer where it found
+ * it; otherwise the IR index of the first loop that moves the pointer is
+ * returned. Loops are assumed to run, so the range may be wider than any
+ * actual run.
+ */
+pub fn static_range(txt: &[BFIsa]) -> Result<RangeInclusive<isize>, usize> {
+    let (mut pos, mut lowest, mut highest) = (0isize, 0isize, 0isize);
+    let mut loops = Vec::new();
+
+    for (idx, op) in txt.iter().enumerate() {
+        match op {
+            BFIsa::Mv(disp) => pos += disp,
+            BFIsa::LoopMvData(disp) => {
+                lowest = lowest.min(pos + disp);
+                highest = highest.max(pos + disp);
+            },
+            BFIsa::LoopMvPtr(_) => return Err(idx),
+            BFIsa::Jmp(_) => loops.push((idx, pos)),
+            BFIsa::Ret(_) => match loops.pop() {
+                Some((_, entry)) if entry == pos => (),
+                Some((start, _)) => return Err(start),
+                None => unreachable!("unbalanced loops in byte code"),
+            },
+            _ => (),
+        }
+        lowest = lowest.min(pos);
+        highest = highest.max(pos);
+    }
+    Ok(lowest..=highest)
+}
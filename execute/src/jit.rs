@@ -0,0 +1,92 @@
+/*
+ * Owner of JIT compiled code. Functions are built in a cranelift JITModule:
+ * the runtime callbacks are imported by symbol name and reached through the
+ * module's relocations, code is written to read/write memory and only made
+ * executable by finalize(), and the memory is released when the Compiled
+ * program is dropped. A program may hold several functions, looked up by
+ * name.
+ */
+
+use crate::Error;
+use cranelift::codegen::{control::ControlPlane, isa::{OwnedTargetIsa, TargetIsa}, Context};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* entry point of a compiled BF program */
+pub const MAIN: &str = "bf_main";
+
+/* runtime callbacks the generated code imports, resolved by module() */
+pub(crate) const RUNTIME: [&str; 3] = ["bf_write", "bf_read", "bf_sync"];
+
+pub struct Compiled {
+    module: Option<JITModule>,
+    funcs: Vec<(String, *const u8)>,
+}
+
+/* the module is only reachable through Compiled, which owns it */
+unsafe impl Send for Compiled {}
+
+/* A module that resolves the runtime callbacks the generated code imports, and the code of other modules in symbols */
+pub(crate) fn module(isa: OwnedTargetIsa, symbols: &[(String, *const u8)]) -> JITModule {
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    let addrs = [crate::write as *const u8, crate::read as *const u8, crate::sync as *const u8];
+    for (name, addr) in RUNTIME.into_iter().zip(addrs) {
+        builder.symbol(name, addr);
+    }
+    for (name, addr) in symbols {
+        builder.symbol(name, *addr);
+    }
+    JITModule::new(builder)
+}
+
+impl Compiled {
+    /* Apply relocations, make the code executable and resolve the addresses of funcs */
+    pub(crate) fn finalize(mut module: JITModule, funcs: &[(&str, FuncId)]) -> Result<Compiled, Error> {
+        module.finalize_definitions().map_err(|_| Error::Jit("Error finalizing compiled code"))?;
+        let funcs = funcs.iter()
+            .map(|(name, id)| (name.to_string(), module.get_finalized_function(*id)))
+            .collect();
+        Ok(Compiled { module: Some(module), funcs })
+    }
+
+    pub fn function(&self, name: &str) -> Option<*const u8> {
+        self.funcs.iter().find(|(func, _)| func == name).map(|(_, code)| *code)
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = &str> {
+        self.funcs.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+/* Compile each function in ctxs on up to threads threads, returns how long each took or its error */
+pub(crate) fn compile_parallel(isa: &dyn TargetIsa, ctxs: &mut [Context], threads: usize) -> Vec<Result<Duration, String>> {
+    let mut results: Vec<_> = ctxs.iter().map(|_| Err(String::new())).collect();
+    let threads = threads.clamp(1, ctxs.len().max(1));
+    let queue = Mutex::new(ctxs.iter_mut().zip(results.iter_mut()));
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let Some((ctx, result)) = next else { break };
+                let start = Instant::now();
+                *result = match ctx.compile(isa, &mut ControlPlane::default()) {
+                    Ok(_) => Ok(start.elapsed()),
+                    Err(err) => Err(err.inner.to_string()),
+                };
+            });
+        }
+    });
+    results
+}
+
+impl Drop for Compiled {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            /* safe as long as no function pointer handed out by function() outlives self */
+            unsafe { module.free_memory() };
+        }
+    }
+}
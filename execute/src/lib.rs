@@ -1,21 +1,45 @@
 use cranelift::{
     codegen::{
         entity::EntityRef,
-        ir::{condcodes::IntCC, types::I8, AbiParam, function::Function, InstBuilder, MemFlags, Signature, UserFuncName},
-        isa,
+        ir::{condcodes::IntCC, types::{I8, I16, I32, I64}, AbiParam, Block, ExternalName, FuncRef, function::Function, InstBuilder, MemFlags, Signature, SourceLoc, Type, UserFuncName, Value},
+        isa::{self, OwnedTargetIsa, TargetIsa},
         settings::{self, Configurable},
         verify_function,
-        control,
-        Context,
+        Context, FinalizedRelocTarget,
     },
     frontend::{FunctionBuilder, FunctionBuilderContext, Variable},
 };
+use cranelift_jit::JITModule;
+use cranelift_module::{FuncId, Linkage, Module, ModuleReloc};
 
 use target_lexicon::Triple;
-use memmap2;
 use std::io::{Read,Write};
+use std::ops::Range;
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
 use std::time::{Duration, Instant}; 
 
+pub mod aot;
+pub mod asm;
+pub mod cache;
+pub mod coverage;
+pub mod diff;
+pub mod disasm;
+pub mod elf;
+pub mod heatmap;
+pub mod jit;
+pub mod perf;
+pub mod profile;
+pub mod srcmap;
+pub mod trace;
+pub mod transpile;
+pub mod wasm;
+
+use disasm::CodeStats;
+use heatmap::TapeStats;
+use profile::Trips;
+use srcmap::SourceMap;
+use trace::{TraceRecord, Tracer};
+
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum BFIsa { 
     Incr(u8),
@@ -29,42 +53,254 @@ pub enum BFIsa {
     Ret(usize),
 }
 
-#[derive(Default,Debug)]
-#[cfg(feature = "profile")]
-pub struct Profile { 
-    pub arith: u64,
-    pub mv:  u64,
-    pub inp: u64, 
-    pub out: u64, 
-    pub jmp: u64,
-    pub ret: u64,
-    pub loopsetz: u64,
-    pub loopmvptr: u64,
-    pub loopmvdata: u64,
-    pub loops: std::collections::HashMap<std::ops::Range<usize>, usize>,
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum CellWidth { 
+    #[default]
+    W8,
+    W16,
+    W32,
 }
 
-#[derive(Debug)]
-pub struct NestingErr(&'static str, usize);
+impl CellWidth { 
+    pub fn bytes(self) -> usize { 
+        match self { 
+            CellWidth::W8 => 1,
+            CellWidth::W16 => 2,
+            CellWidth::W32 => 4,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum TapeModel { 
+    #[default]
+    Wrap,       /* pointer wraps around at either end of the tape */
+    Bounded,    /* moving off either end of the tape is an error */
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum EofPolicy { 
+    #[default]
+    Zero,       /* ',' at the end of input stores 0 */
+    MinusOne,   /* stores -1, all bits of the cell set */
+    Unchanged,  /* leaves the cell as it was */
+}
+
+#[derive(Clone, Debug)]
+pub struct Options { 
+    pub heap_size: usize,   /* in cells */
+    pub peephole: bool,     /* fold clear/move loops into LoopSetZero/LoopMvData/LoopMvPtr */
+    pub cell_width: CellWidth,
+    pub tape: TapeModel,
+    pub eof: EofPolicy,
+}
+
+impl Options { 
+    pub fn new(heap_size: usize) -> Self { 
+        Options { 
+            heap_size, 
+            peephole: true, 
+            cell_width: CellWidth::default(),
+            tape: TapeModel::default(),
+            eof: EofPolicy::default(),
+        }
+    }
+}
+
+/* Cranelift settings for compiled code, see make_isa() */
+#[derive(Clone, Debug)]
+pub struct JitOptions { 
+    pub opt_level: settings::OptLevel,
+    pub regalloc: settings::RegallocAlgorithm,
+    pub verify: bool,           /* run the CLIF verifier before and during compilation */
+    pub frame_pointers: bool,
+    pub probestack: bool,       /* inline stack probes for large frames */
+    pub isa_flags: Vec<(String, String)>,  /* e.g. ("has_avx", "false"), an empty value enables the flag */
+    pub max_function_ops: usize,    /* bigger programs are split into one function per run of top level ops */
+    pub threads: usize,             /* compiling the functions of a split program */
+}
+
+impl Default for JitOptions { 
+    fn default() -> Self { 
+        JitOptions { 
+            opt_level: settings::OptLevel::Speed,
+            regalloc: settings::RegallocAlgorithm::Backtracking,
+            verify: true,
+            frame_pointers: false,
+            probestack: false,
+            isa_flags: Vec::new(),
+            max_function_ops: 10000,
+            threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        }
+    }
+}
+
+/* Hook called at sync points: loop tests and after I/O. Gets (ir index, ptr, tape bytes), returns false to stop */
+pub type SyncHook = Box<dyn FnMut(usize, usize, &[u8]) -> bool + Send>;
+
+/* Runtime environment shared by the interpreter and JIT generated code */
+pub struct Env { 
+    pub input: Box<dyn Read + Send>,
+    pub output: Box<dyn Write + Send>,
+    pub sync: Option<SyncHook>,
+}
+
+impl Default for Env { 
+    fn default() -> Self { 
+        Env { 
+            input: Box::new(std::io::stdin()),
+            output: Box::new(std::io::stdout()),
+            sync: None,
+        }
+    }
+}
+
+/* Per op execution counts, indexed like ProgramState::txt */
+#[derive(Clone, Debug, Default)]
+pub struct OpCounts { 
+    pub exec: Vec<u64>,
+    /* loop ops only: times the tested cell was non zero, i.e. the loop was entered ([ and folded loops) or repeated (]) */
+    pub nonzero: Vec<u64>,
+}
+
+impl OpCounts { 
+    pub fn new(len: usize) -> Self { 
+        OpCounts { exec: vec![0; len], nonzero: vec![0; len] }
+    }
+}
+
+/* Settings and results of interpret_tiered() */
+#[derive(Clone, Debug, Default)]
+pub struct Tiering { 
+    pub threshold: u64,             /* iterations of a loop before it is compiled */
+    pub compiled: Vec<usize>,       /* the '[' of each compiled loop, in the order they were compiled */
+    pub compile_time: Duration,
+}
+
+impl Tiering { 
+    pub fn new(threshold: u64) -> Self { 
+        Tiering { threshold: threshold.max(1), ..Default::default() }
+    }
+}
+
+/* An op of the program and the source it was built from */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location { 
+    pub idx: usize,             /* index in txt */
+    pub span: Range<usize>,     /* source bytes */
+    pub line: usize,
+    pub col: usize,
+}
+
+impl std::fmt::Display for Location { 
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { 
+        write!(f, "{}:{} (IR #{})", self.line, self.col, self.idx)
+    }
+}
 
 #[derive(Debug)]
-pub struct JitErr(&'static str);
+pub enum Error { 
+    Nesting { unmatched: char, pos: usize, line: usize, col: usize },    /* '[' or ']' without a partner at source byte pos */
+    Unsupported(&'static str),                                  /* the engine cannot run this configuration */
+    Isa { triple: String, error: String },                      /* no code generator for the target */
+    Verifier { isa: String, errors: Vec<String>, clif: String },  /* generated CLIF is malformed */
+    Codegen { isa: String, error: String, clif: String },        /* Cranelift failed to compile valid CLIF */
+    Jit(&'static str),                                          /* any other failure to build or load code */
+    Io { at: Option<Location>, error: std::io::Error },         /* a '.' or ',' failed, or flushing the output at the end */
+    Trace(std::io::Error),                                      /* writing the execution trace failed */
+    Perf(std::io::Error),                                       /* writing the perf map or jitdump failed */
+    OutOfBounds { at: Option<Location> },                       /* the pointer left a bounded tape, compiled code does not know where */
+    OutOfFuel { at: Location },                                 /* the op that would have gone over the limit from set_fuel() */
+    Cancelled { at: Option<Location> },                         /* stopped by ProgramState::cancel or env.sync */
+}
+
+impl Error { 
+    /* the CLIF of the function that failed, if it got that far */
+    pub fn clif(&self) -> Option<&str> { 
+        match self { 
+            Error::Verifier { clif, .. } | Error::Codegen { clif, .. } => Some(clif),
+            _ => None,
+        }
+    }
+
+    /* where in the program it happened, if known */
+    pub fn location(&self) -> Option<&Location> { 
+        match self { 
+            Error::Io { at, .. } | Error::OutOfBounds { at } | Error::Cancelled { at } => at.as_ref(),
+            Error::OutOfFuel { at } => Some(at),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error { 
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { 
+        match self { 
+            Error::Nesting { unmatched, line, col, .. } => write!(f, "Unmatched '{}' at {}:{}", unmatched, line, col)?,
+            Error::Unsupported(msg) | Error::Jit(msg) => write!(f, "{}", msg)?,
+            Error::Isa { triple, error } => write!(f, "Unsupported target {} : {}", triple, error)?,
+            Error::Verifier { isa, errors, .. } => { 
+                write!(f, "Generated code for {} failed verification with {} errors", isa, errors.len())?;
+                errors.iter().try_for_each(|error| write!(f, "\n  {}", error))?;
+            },
+            Error::Codegen { isa, error, .. } => write!(f, "Error compiling code for {} : {}", isa, error)?,
+            Error::Io { error, .. } => write!(f, "I/O error : {} ({:?})", error, error.kind())?,
+            Error::Trace(error) => write!(f, "Error writing trace : {}", error)?,
+            Error::Perf(error) => write!(f, "Error writing perf map or jitdump : {}", error)?,
+            Error::OutOfBounds { .. } => write!(f, "Tape pointer out of bounds")?,
+            Error::OutOfFuel { .. } => write!(f, "Out of fuel")?,
+            Error::Cancelled { .. } => write!(f, "Execution cancelled")?,
+        }
+        match self.location() { 
+            Some(at) => write!(f, " at {}", at),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Error { 
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> { 
+        match self { 
+            Error::Io { error, .. } | Error::Trace(error) | Error::Perf(error) => Some(error),
+            _ => None,
+        }
+    }
+}
 
 pub struct ProgramState { 
     ptr:  usize,
     pc:   usize,
     heap: Vec<u8>, 
+    cell_width: CellWidth,
+    tape: TapeModel,
+    eof: EofPolicy,
+    pub env: Env,
     pub txt:  Vec<BFIsa>,
-    jit_txt: Option<Vec<u8>>,
-    #[cfg(feature = "profile")]
-    pub profile: Profile,
+    pub spans: Vec<Range<usize>>,   /* source bytes each op in txt was built from */
+    pub srcmap: SourceMap,
+    jit: Option<jit::Compiled>,
+    jit_counters: Option<Vec<u64>>,    /* Some when the compiled code is profiled */
+    code_stats: Option<CodeStats>,
+    function_stats: Vec<(String, CodeStats)>,
+    pub profile: profile::Profile,
+    fuel: u64,                              /* ops the interpreter may still run, counts down from u64::MAX without a limit */
+    fuel_limited: bool,
+    pub cancel: Option<Arc<AtomicBool>>,    /* set from another thread to stop the interpreter at its next ']' */
+    pub jit_options: JitOptions,        /* used by jit_compile*() and the loops interpret_tiered() compiles */
+    pub code_cache: Option<cache::CodeCache>,   /* where jit_compile*() keeps compiled programs across runs */
+    pub perf: perf::PerfOptions,        /* tell perf about the functions jit_compile*() and interpret_tiered() compile */
 }
 
 
 
 impl ProgramState { 
-    pub fn new(src: &[u8], heap_sz: usize) -> Result<ProgramState, NestingErr> { 
+    pub fn new(src: &[u8], heap_sz: usize) -> Result<ProgramState, Error> { 
+        Self::with_options(src, &Options::new(heap_sz))
+    }
+
+    pub fn with_options(src: &[u8], opts: &Options) -> Result<ProgramState, Error> { 
         let mut code = Vec::new();
+        let mut spans: Vec<Range<usize>> = Vec::new();
         let mut nest_stk = Vec::new();
 
         for (pos, byte) in src.iter().enumerate() {
@@ -72,8 +308,12 @@ impl ProgramState {
                 b'+' | b'-' => { 
                     let incr = if *byte == b'+' {1} else {1u8.wrapping_neg()}; 
                     if let Some(BFIsa::Incr(rhs)) = code.last_mut() { 
-                        *rhs = rhs.wrapping_add(incr);
-                        continue;
+                        /* wider cells read Incr as a signed byte, so only merge while it fits */
+                        if opts.cell_width == CellWidth::W8 || (*rhs as i8).checked_add(incr as i8).is_some() { 
+                            *rhs = rhs.wrapping_add(incr);
+                            spans.last_mut().unwrap().end = pos + 1;
+                            continue;
+                        }
                     }
                     BFIsa::Incr(incr)
                 },
@@ -83,6 +323,7 @@ impl ProgramState {
                     let incr = if *byte == b'>' {1} else {-1}; 
                     if let Some(BFIsa::Mv(curr)) = code.last_mut() {
                         *curr += incr; 
+                        spans.last_mut().unwrap().end = pos + 1;
                         continue;
                     }; 
                     BFIsa::Mv(incr) 
@@ -92,30 +333,41 @@ impl ProgramState {
                     BFIsa::Jmp(0)
                 },
                 b']' => { 
-                    if let Some((ret_addr, _loc)) = nest_stk.pop() { 
+                    if let Some((ret_addr, loc)) = nest_stk.pop() { 
                         code[ret_addr] = BFIsa::Jmp(code.len());
 
-                        match code.as_slice() { 
+                        let folded = match code.as_slice() { 
+                            _ if !opts.peephole => None,
+
                             [.., BFIsa::Jmp(_), BFIsa::Incr(n)] if n % 2 == 1 => {
-                                code.drain(code.len() - 2..); 
-                                BFIsa::LoopSetZero
+                                Some((2, BFIsa::LoopSetZero))
                             }, 
 
                             &[.., BFIsa::Jmp(_), BFIsa::Incr(255), BFIsa::Mv(pdat), BFIsa::Incr(1), BFIsa::Mv(pidx)]
                                 if pdat == -pidx => {
-                                    code.drain(code.len() - 5..);
-                                    BFIsa::LoopMvData(pdat)
+                                    Some((5, BFIsa::LoopMvData(pdat)))
                             },
 
                             &[.., BFIsa::Jmp(_), BFIsa::Mv(pptr)] => {
-                                code.drain(code.len() - 2..);
-                                BFIsa::LoopMvPtr(pptr)
+                                Some((2, BFIsa::LoopMvPtr(pptr)))
                             },
 
-                            _ => BFIsa::Ret(ret_addr),
+                            _ => None,
+                        };
+
+                        /* folded loops cover the source from '[' to ']' */
+                        match folded { 
+                            Some((len, instr)) => { 
+                                code.truncate(code.len() - len);
+                                spans.truncate(spans.len() - len);
+                                code.push(instr);
+                                spans.push(loc..pos + 1);
+                                continue;
+                            },
+                            None => BFIsa::Ret(ret_addr),
                         }
                     } else {
-                        return Err(NestingErr("Nesting Err ] @", pos));
+                        return Err(nesting(src, ']', pos));
                     }
                 },
                 _ => {
@@ -123,127 +375,662 @@ impl ProgramState {
                 }
             };
             code.push(instr);
+            spans.push(pos..pos + 1);
         }
 
         if let Some((_unpaired_jmp, pos)) = nest_stk.pop() { 
-            return Err(NestingErr("Nesting Err [ @", pos));
+            return Err(nesting(src, '[', pos));
         }
 
         Ok(ProgramState { 
             ptr: 0, 
             pc: 0, 
-            heap: vec![0; heap_sz], 
+            heap: vec![0; opts.heap_size * opts.cell_width.bytes()], 
+            cell_width: opts.cell_width,
+            tape: opts.tape,
+            eof: opts.eof,
+            env: Env::default(),
+            profile: profile::Profile::new(code.len()),
             txt: code, 
-            jit_txt: None,
-            #[cfg(feature = "profile")] 
-            profile: Profile::default()
+            spans,
+            srcmap: SourceMap::new(src),
+            jit: None,
+            jit_counters: None,
+            code_stats: None,
+            function_stats: Vec::new(),
+            jit_options: JitOptions::default(),
+            code_cache: None,
+            perf: perf::PerfOptions::default(),
+            fuel: u64::MAX,
+            fuel_limited: false,
+            cancel: None,
         })
     }
 
-    pub fn interpret(&mut self) -> Result<(i32,Duration), &'static str> {
+    pub fn interpret(&mut self) -> Result<(i32,Duration), Error> {
+        self.check_interpretable()?;
         let bm_start = Instant::now(); 
-        'program: loop { 
-            #[cfg(feature = "profile")]
-            {
-                match self.txt[self.pc] { 
-                    BFIsa::Incr(_) => self.profile.arith += 1,
-                    BFIsa::Out => self.profile.out += 1,
-                    BFIsa::In => self.profile.inp += 1,
-                    BFIsa::Mv(_) => self.profile.mv += 1,
-                    BFIsa::Jmp(_) => self.profile.jmp += 1,
-                    BFIsa::Ret(addr) => {
-                        self.profile.ret += 1;
-                        *self.profile
-                            .loops
-                            .entry(addr..self.pc+1).
-                            or_default() += 1;
-                    },
-                    BFIsa::LoopSetZero => self.profile.loopsetz += 1,
-                    BFIsa::LoopMvData(_) => self.profile.loopmvdata += 1,
-                    BFIsa::LoopMvPtr(_) => self.profile.loopmvptr += 1,
-                }
-                    
+        while self.pc < self.txt.len() { 
+            self.step()?;
+        }
+        self.env.output.flush().map_err(|error| Error::Io { at: None, error })?;
+        Ok((0, bm_start.elapsed()))
+    }
+
+    /* Same as interpret() but counts every executed op */
+    pub fn interpret_counted(&mut self, counts: &mut OpCounts) -> Result<(i32,Duration), Error> {
+        self.check_interpretable()?;
+        if counts.exec.len() != self.txt.len() { 
+            *counts = OpCounts::new(self.txt.len());
+        }
+
+        let bm_start = Instant::now(); 
+        while self.pc < self.txt.len() { 
+            self.count(counts);
+            self.step()?;
+        }
+        self.env.output.flush().map_err(|error| Error::Io { at: None, error })?;
+        Ok((0, bm_start.elapsed()))
+    }
+
+    #[inline(always)]
+    fn count(&self, counts: &mut OpCounts) { 
+        let idx = self.pc;
+        counts.exec[idx] += 1;
+        if self.heap[self.ptr] != 0 && !matches!(self.txt[idx], BFIsa::Incr(_) | BFIsa::Mv(_) | BFIsa::In | BFIsa::Out) { 
+            counts.nonzero[idx] += 1;
+        }
+    }
+
+    /* Same as interpret_counted() into self.profile, also recording loop trip counts */
+    pub fn interpret_profiled(&mut self) -> Result<(i32,Duration), Error> {
+        self.check_interpretable()?;
+        let mut profile = std::mem::take(&mut self.profile);
+        if profile.ops.exec.len() != self.txt.len() { 
+            profile = profile::Profile::new(self.txt.len());
+        }
+        let mut trip = vec![0u64; self.txt.len()];
+
+        let bm_start = Instant::now(); 
+        let result = loop { 
+            if self.pc >= self.txt.len() { 
+                break Ok((0, bm_start.elapsed()));
             }
 
+            let idx = self.pc;
+            self.count(&mut profile.ops);
+            match (self.txt[idx], self.heap[self.ptr] != 0) { 
+                (BFIsa::Jmp(_), true) => trip[idx] = 1,
+                (BFIsa::Jmp(_), false) => profile.trips[idx].record(0),
+                (BFIsa::Ret(start), true) => trip[start] += 1,
+                (BFIsa::Ret(start), false) => profile.trips[start].record(trip[start]),
+                _ => (),
+            }
+            if let Err(err) = self.step() { 
+                break Err(err);
+            }
+        };
+        self.profile = profile;
+        let result = result?;
+        self.env.output.flush().map_err(|error| Error::Io { at: None, error })?;
+        Ok(result)
+    }
 
-            match self.txt[self.pc] { 
-                BFIsa::Incr(rhs) => self.heap[self.ptr] = self.heap[self.ptr].wrapping_add(rhs), 
-                BFIsa::Out => print!("{}", self.heap[self.ptr] as char),
-                BFIsa::In => { 
-                    use std::io::Read; 
-                    let _ = match std::io::stdin().read_exact(&mut self.heap[self.ptr..self.ptr+1]) { 
-                        Ok(()) => 0, 
-                        Err(_) => { return Err("Error reading from stdio"); }, 
-                    };
-                }, 
-                BFIsa::Mv(disp) => { 
-                    let heap_sz = self.heap.len() as isize; 
-                    let disp = (heap_sz + (disp % heap_sz)) as usize; 
-                    self.ptr = (self.ptr + disp) % heap_sz as usize; 
-                }, 
-                BFIsa::LoopSetZero => { 
-                    self.heap[self.ptr] = 0;
-                },
-                BFIsa::LoopMvData(n) => { 
-                    let len = self.heap.len() as isize; 
-                    let n = (len + n % len) as usize; 
-                    let to = (self.ptr + n) % len as usize;
+    /*
+     * Same as interpret() but a loop is compiled with Cranelift once it has
+     * iterated tiering.threshold times, and from then on runs compiled each
+     * time the interpreter reaches its '['. A loop that gets hot while it is
+     * running is entered again at its test, so long running loops move over
+     * straight away. Loops that fail to compile stay interpreted, and nothing
+     * is compiled while fuel or cancel are set as compiled code checks neither.
+     * A loop calls the code of loops inside it that were compiled before it
+     * rather than compiling them again, which costs a call and the cached
+     * cells around it on every entry to the inner loop.
+     */
+    pub fn interpret_tiered(&mut self, tiering: &mut Tiering) -> Result<(i32,Duration), Error> { 
+        self.check_interpretable()?;
+        let mut iterations = vec![0u64; self.txt.len()];
+        let mut compiled: Vec<Option<jit::Compiled>> = (0..self.txt.len()).map(|_| None).collect();
 
-                    self.heap[to] = self.heap[to].wrapping_add(self.heap[self.ptr]);
-                    self.heap[self.ptr] = 0;
+        let bm_start = Instant::now(); 
+        while self.pc < self.txt.len() { 
+            match self.txt[self.pc] { 
+                BFIsa::Jmp(end) if compiled[self.pc].is_some() => { 
+                    let code = compiled[self.pc].as_ref().and_then(|code| code.function(&loop_name(self.pc))).unwrap();
+                    let fault = self.call(code, std::ptr::null_mut());
+                    self.returned(fault)?;
+                    self.pc = end + 1;
+                    continue;
                 },
-               BFIsa::LoopMvPtr(n) => { 
-                    let len = self.heap.len() as isize; 
-                    let n = (len + n % len) as usize; 
-                    loop { 
-                        if self.heap[self.ptr] == 0 {
-                            break;
+                BFIsa::Ret(start) if self.heap[self.ptr] != 0 && self.fuel().is_none() && self.cancel.is_none() => { 
+                    iterations[start] += 1;
+                    if iterations[start] == tiering.threshold { 
+                        let compile_start = Instant::now();
+                        compiled[start] = self.compile_loop(start, &compiled).ok();
+                        tiering.compile_time += compile_start.elapsed();
+                        if compiled[start].is_some() { 
+                            tiering.compiled.push(start);
+                            self.pc = start;
+                            continue;
                         }
-                        self.ptr = (self.ptr + n) % len as usize;
                     }
+                },
+                _ => (),
+            }
+            self.step()?;
+        }
+        self.env.output.flush().map_err(|error| Error::Io { at: None, error })?;
+        Ok((0, bm_start.elapsed()))
+    }
+
+    /* The loop starting at the '[' at start, alone in a module that calls the loops inside it found in compiled */
+    fn compile_loop(&self, start: usize, compiled: &[Option<jit::Compiled>]) -> Result<jit::Compiled, Error> { 
+        let end = match self.txt[start] { 
+            BFIsa::Jmp(end) => end,
+            _ => return Err(Error::Jit("Not the start of a loop")),
+        };
+        let name = loop_name(start);
+
+        /* outlined the way jit_define() splits a program, the regions resolve to code of the other modules */
+        let (mut outlined, mut symbols) = (Vec::new(), Vec::new());
+        let mut idx = start + 1;
+        while idx < end { 
+            match (self.txt[idx], compiled[idx].as_ref().and_then(|inner| inner.function(&loop_name(idx)))) { 
+                (BFIsa::Jmp(inner_end), Some(code)) => { 
+                    outlined.push(idx..inner_end + 1);
+                    symbols.push((region_name(idx), code));
+                    idx = inner_end + 1;
+                },
+                _ => idx += 1,
+            }
+        }
+
+        let mut module = jit::module(make_isa(Triple::host(), &self.jit_options, false)?, &symbols);
+        let (id, stats, ops) = self.lower(&mut module, &name, start..end + 1, &outlined, false, Instrument::default())?;
+        let compiled = jit::Compiled::finalize(module, &[(&name, id)])?;
+        if self.perf.enabled() { 
+            perf::register(self, &compiled, &[(&name, stats.code_size, &ops)]).map_err(Error::Perf)?;
+        }
+        Ok(compiled)
+    }
+
+    /* Run generated code with the current tape and pointer, which it updates */
+    fn call(&mut self, code: *const u8, counters: *mut u64) -> *mut Fault { 
+        unsafe { 
+            let jit_fn : unsafe extern "C" fn(*mut u8, *mut Env, *mut usize, *mut u64) -> *mut Fault = 
+                std::mem::transmute(code);
+            jit_fn(self.heap.as_mut_ptr(), &mut self.env, &mut self.ptr, counters)
+        }
+    }
+
+    /* Same as interpret() but counts reads and writes of every cell and how far the pointer moves */
+    pub fn interpret_tape_stats(&mut self, stats: &mut TapeStats) -> Result<(i32,Duration), Error> {
+        self.check_interpretable()?;
+        if stats.reads.len() != self.heap.len() { 
+            *stats = TapeStats::new(self.heap.len());
+        }
 
+        let bm_start = Instant::now(); 
+        while self.pc < self.txt.len() { 
+            let ptr = self.ptr;
+            match self.txt[self.pc] { 
+                BFIsa::Incr(_) | BFIsa::LoopSetZero => { 
+                    stats.read(ptr);
+                    stats.write(ptr);
                 },
-                BFIsa::Jmp(target) => { 
-                    if self.heap[self.ptr] == 0 { 
-                        self.pc = target; 
+                BFIsa::Out | BFIsa::Jmp(_) | BFIsa::Ret(_) => stats.read(ptr),
+                BFIsa::In => stats.write(ptr),
+                BFIsa::Mv(disp) => stats.moved(disp),
+                BFIsa::LoopMvData(n) => { 
+                    stats.read(ptr);
+                    if self.heap[ptr] != 0 { 
+                        let to = self.offset_ptr(n)?;
+                        stats.read(to);
+                        stats.write(to);
+                        stats.write(ptr);
+                        stats.reach(n);
                     }
                 },
-                BFIsa::Ret(target) => { 
-                    if self.heap[self.ptr] != 0 { 
-                        self.pc = target; 
+                BFIsa::LoopMvPtr(n) => { 
+                    /* run the scan here to see every cell it tests */
+                    self.charge_fuel()?;
+                    stats.read(ptr);
+                    while self.heap[self.ptr] != 0 { 
+                        self.scan_move(n)?;
+                        stats.moved(n);
+                        stats.read(self.ptr);
                     }
-                }
+                    self.pc += 1;
+                    continue;
+                },
+            }
+            self.step()?;
+        }
+        self.env.output.flush().map_err(|error| Error::Io { at: None, error })?;
+        Ok((0, bm_start.elapsed()))
+    }
+
+    /* Same as interpret() but calls env.sync at loop tests and after I/O, like jit_compile_synced() code */
+    pub fn interpret_synced(&mut self) -> Result<(i32,Duration), Error> {
+        self.check_interpretable()?;
+        let mut sync = self.env.sync.take();
+        let bm_start = Instant::now(); 
+        let result = loop { 
+            if self.pc >= self.txt.len() { 
+                break Ok((0, bm_start.elapsed()));
             }
 
-            self.pc += 1;
+            let idx = self.pc;
+            let (before, after) = match self.txt[idx] { 
+                BFIsa::Jmp(_) | BFIsa::Ret(_) => (true, false),
+                BFIsa::In | BFIsa::Out => (false, true),
+                _ => (false, false),
+            };
 
-            if self.txt.len() == self.pc {
-                break 'program;
+            if before && !sync.as_mut().is_none_or(|hook| hook(idx, self.ptr, &self.heap)) { 
+                break Err(Error::Cancelled { at: Some(self.location(idx)) });
+            }
+            if let Err(err) = self.step() { 
+                break Err(err);
             }
+            if after && !sync.as_mut().is_none_or(|hook| hook(idx, self.ptr, &self.heap)) { 
+                break Err(Error::Cancelled { at: Some(self.location(idx)) });
+            }
+        };
+        self.env.sync = sync;
+        let result = result?;
+        self.env.output.flush().map_err(|error| Error::Io { at: None, error })?;
+        Ok(result)
+    }
+
+    /* Same as interpret() but hands every executed op to the tracer */
+    pub fn interpret_traced<W: Write>(&mut self, tracer: &mut Tracer<W>) -> Result<(i32,Duration), Error> {
+        self.check_interpretable()?;
+        let bm_start = Instant::now(); 
+        let mut nstep = 0u64; 
+        while self.pc < self.txt.len() { 
+            let (idx, ptr) = (self.pc, self.ptr);
+            let before = self.heap[ptr];
+
+            self.step()?;
+
+            if tracer.wants(nstep, &self.spans[idx]) { 
+                let record = TraceRecord { 
+                    step: nstep, 
+                    idx, 
+                    span: self.spans[idx].clone(), 
+                    ptr, 
+                    before, 
+                    after: self.heap[ptr],
+                };
+                tracer.record(&record).map_err(Error::Trace)?;
+            }
+            nstep += 1;
         }
+        tracer.flush().map_err(Error::Trace)?;
+        self.env.output.flush().map_err(|error| Error::Io { at: None, error })?;
         Ok((0, bm_start.elapsed()))
     }
 
-    pub fn jit_compile(&mut self, clir: bool) -> Result<i32, JitErr> {
-        // Compiler setup  
-        let mut builder = settings::builder();
-        builder.set("opt_level", "speed").unwrap(); 
-        builder.set("preserve_frame_pointers", "false").unwrap(); 
+    /* Limit the ops the interpreter may still run, folded loops count as one */
+    pub fn set_fuel(&mut self, fuel: Option<u64>) { 
+        self.fuel = fuel.unwrap_or(u64::MAX);
+        self.fuel_limited = fuel.is_some();
+    }
+
+    /* Ops left, None when there is no limit */
+    pub fn fuel(&self) -> Option<u64> { 
+        self.fuel_limited.then_some(self.fuel)
+    }
+
+    pub fn ptr(&self) -> usize { 
+        self.ptr
+    }
+
+    /* Code from the last jit_compile*() call */
+    pub fn compiled(&self) -> Option<&jit::Compiled> { 
+        self.jit.as_ref()
+    }
+
+    /* Size, shape and compile time of the code from the last jit_compile*() call, summed over its functions */
+    pub fn code_stats(&self) -> Option<&CodeStats> { 
+        self.code_stats.as_ref()
+    }
+
+    /* The same for each function, bf_main first, see JitOptions::max_function_ops */
+    pub fn function_stats(&self) -> &[(String, CodeStats)] { 
+        &self.function_stats
+    }
 
-        let flags = settings::Flags::new(builder); 
-        let isa = match isa::lookup(Triple::host()) { 
-            Err(err) => panic!("Error looking up target : {}", err), 
-            Ok(isa_builder) => isa_builder.finish(flags).unwrap(), 
+    /* Raw tape bytes, cells are stored little endian */
+    pub fn tape(&self) -> &[u8] { 
+        &self.heap
+    }
+
+    /* The op at idx and its source */
+    pub fn location(&self, idx: usize) -> Location { 
+        let span = self.spans[idx].clone();
+        let (line, col) = self.srcmap.line_col(span.start);
+        Location { idx, span, line, col }
+    }
+
+    fn check_interpretable(&self) -> Result<(), Error> { 
+        match self.cell_width { 
+            CellWidth::W8 => Ok(()),
+            _ => Err(Error::Unsupported("The interpreter only supports 8 bit cells")),
+        }
+    }
+
+    #[inline(always)]
+    fn offset_ptr(&self, disp: isize) -> Result<usize, Error> { 
+        let heap_sz = self.heap.len() as isize; 
+        let to = self.ptr as isize + disp;
+        match self.tape { 
+            TapeModel::Wrap => Ok(to.rem_euclid(heap_sz) as usize),
+            TapeModel::Bounded if (0..heap_sz).contains(&to) => Ok(to as usize),
+            TapeModel::Bounded => Err(Error::OutOfBounds { at: Some(self.location(self.pc)) }),
+        }
+    }
+
+    #[inline(always)]
+    fn charge_fuel(&mut self) -> Result<(), Error> { 
+        if self.fuel == 0 { 
+            return Err(Error::OutOfFuel { at: self.location(self.pc) });
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+
+    /* One move of a folded [>], which costs fuel and can be cancelled like a pass of the loop it stands for */
+    #[inline(always)]
+    fn scan_move(&mut self, n: isize) -> Result<(), Error> { 
+        self.charge_fuel()?;
+        if self.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed)) { 
+            return Err(Error::Cancelled { at: Some(self.location(self.pc)) });
+        }
+        self.ptr = self.offset_ptr(n)?;
+        Ok(())
+    }
+
+    /* Execute the op at pc and advance pc */
+    #[inline(always)]
+    fn step(&mut self) -> Result<(), Error> { 
+        self.charge_fuel()?;
+
+
+        match self.txt[self.pc] { 
+            BFIsa::Incr(rhs) => self.heap[self.ptr] = self.heap[self.ptr].wrapping_add(rhs), 
+            BFIsa::Out => { 
+                if let Err(error) = self.env.output.write_all(&self.heap[self.ptr..self.ptr+1]) { 
+                    return Err(Error::Io { at: Some(self.location(self.pc)), error }); 
+                }
+            },
+            BFIsa::In => { 
+                match self.env.input.read_exact(&mut self.heap[self.ptr..self.ptr+1]) { 
+                    Ok(()) => (),
+                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => match self.eof { 
+                        EofPolicy::Zero => self.heap[self.ptr] = 0,
+                        EofPolicy::MinusOne => self.heap[self.ptr] = 255,
+                        EofPolicy::Unchanged => (),
+                    },
+                    Err(error) => return Err(Error::Io { at: Some(self.location(self.pc)), error }),
+                }
+            }, 
+            BFIsa::Mv(disp) => { 
+                self.ptr = self.offset_ptr(disp)?;
+            }, 
+            BFIsa::LoopSetZero => { 
+                self.heap[self.ptr] = 0;
+            },
+            BFIsa::LoopMvData(n) => { 
+                if self.heap[self.ptr] != 0 { 
+                    let to = self.offset_ptr(n)?;
+                    self.heap[to] = self.heap[to].wrapping_add(self.heap[self.ptr]);
+                    self.heap[self.ptr] = 0;
+                }
+            },
+            BFIsa::LoopMvPtr(n) => { 
+                while self.heap[self.ptr] != 0 { 
+                    self.scan_move(n)?;
+                }
+            },
+            BFIsa::Jmp(target) => { 
+                if self.heap[self.ptr] == 0 { 
+                    self.pc = target; 
+                }
+            },
+            BFIsa::Ret(target) => { 
+                if self.heap[self.ptr] != 0 { 
+                    if self.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed)) { 
+                        return Err(Error::Cancelled { at: Some(self.location(self.pc)) });
+                    }
+                    self.pc = target; 
+                }
+            }
+        }
+
+        self.pc += 1;
+        Ok(())
+    }
+
+    pub fn jit_compile(&mut self, clir: bool) -> Result<i32, Error> {
+        self.jit_lower(clir, Instrument::default())
+    }
+
+    /* Like jit_compile() but the generated code calls env.sync at loop tests and after I/O */
+    pub fn jit_compile_synced(&mut self, clir: bool) -> Result<i32, Error> {
+        self.jit_lower(clir, Instrument { sync: true, ..Default::default() })
+    }
+
+    /* Like jit_compile() but jit_exec() records the execution profile in self.profile */
+    pub fn jit_compile_profiled(&mut self, clir: bool) -> Result<i32, Error> {
+        self.jit_lower(clir, Instrument { profile: true, ..Default::default() })
+    }
+
+    fn jit_lower(&mut self, clir: bool, instrument: Instrument) -> Result<i32, Error> { 
+        let isa = make_isa(Triple::host(), &self.jit_options, false)?;
+        let key = self.cache_key(&*isa, instrument);
+        let mut module = jit::module(isa, &[]);
+
+        /* -v shows the CLIF and listing, which need a compile */
+        let cached = self.code_cache.as_ref().filter(|_| !clir).and_then(|cache| cache.load(&key, self.txt.len()));
+        let functions = match cached { 
+            Some(cached) => { 
+                let ids = cache::define(&mut module, &cached)?;
+                cached.into_iter().zip(ids).map(|(func, id)| (func.name.clone(), id, func.stats(), func.ops)).collect()
+            },
+            None => self.jit_define(&mut module, clir, instrument, &key)?,
+        };
+
+        let mut stats = CodeStats { cached: functions.iter().all(|(_, _, function, _)| function.cached), ..Default::default() };
+        for (_, _, function, _) in &functions { 
+            stats.add(function);
+        }
+        let funcs: Vec<_> = functions.iter().map(|(name, id, _, _)| (name.as_str(), *id)).collect();
+        self.jit = None;    // free the previous code before mapping the new one
+        let compiled = jit::Compiled::finalize(module, &funcs)?;
+        if self.perf.enabled() { 
+            let symbols: Vec<_> = functions.iter().map(|(name, _, function, ops)| (name.as_str(), function.code_size, ops)).collect();
+            perf::register(self, &compiled, &symbols).map_err(Error::Perf)?;
+        }
+        self.jit = Some(compiled);
+        let trip_base = 2 * self.txt.len() + 1;
+        self.jit_counters = instrument.profile.then(|| vec![0; trip_base + stats.loops * TRIP_SLOTS]);
+        self.code_stats = Some(stats);
+        self.function_stats = functions.into_iter().map(|(name, _, function, _)| (name, function)).collect();
+
+        Ok(0)
+    }
+
+    /* Compile the program into module, split by plan(), and store it in the code cache under key */
+    fn jit_define(&self, module: &mut JITModule, clir: bool, instrument: Instrument, key: &[u8]) -> Result<Vec<(String, FuncId, CodeStats, perf::CodeOps)>, Error> { 
+        let mut plan = Vec::new();
+        self.plan(0..self.txt.len(), self.jit_options.max_function_ops.max(1), &mut plan);
+
+        /* build serially, compile on all threads, then define in module */
+        let (mut units, mut ctxs) = (Vec::new(), Vec::new());
+        for (no, (range, outlined)) in plan.iter().enumerate() { 
+            let (name, linkage) = match no { 
+                0 => (jit::MAIN.to_string(), Linkage::Export),
+                _ => (region_name(range.start), Linkage::Local),
+            };
+            let (id, ctx, stats) = self.prepare(module, &name, linkage, range.clone(), outlined, instrument)?;
+            units.push((name, id, stats));
+            ctxs.push(ctx);
+        }
+        let compiled = jit::compile_parallel(module.isa(), &mut ctxs, self.jit_options.threads);
+
+        let mut functions = Vec::new();
+        let mut cached = Vec::new();
+        for ((name, id, mut function), (ctx, compiled)) in units.into_iter().zip(ctxs.iter().zip(compiled)) { 
+            function.compile = compiled.map_err(|error| { 
+                Error::Codegen { isa: module.isa().triple().to_string(), error, clif: ctx.func.display().to_string() }
+            })?;
+            let code = ctx.compiled_code().ok_or(Error::Jit("Compiled code missing after compilation"))?;
+            let relocs: Vec<_> = code.buffer.relocs().iter().map(|reloc| ModuleReloc::from_mach_reloc(reloc, &ctx.func, id)).collect();
+            module.define_function_bytes(id, code.buffer.alignment as u64, code.code_buffer(), &relocs)
+                .map_err(|_| Error::Jit("Error defining compiled code"))?;
+            self.finish(module, ctx, &mut function, clir)?;
+            let ops = perf::code_ops(code);
+            if self.code_cache.is_some() { 
+                cached.push(cache::CachedFunction::capture(module, &name, code, &relocs, &function, &ops));
+            }
+            functions.push((name, id, function, ops));
+        }
+
+        /* a cache that cannot be written only costs the next run a compile */
+        if let (Some(cache), Some(cached)) = (&self.code_cache, cached.into_iter().collect::<Option<Vec<_>>>()) { 
+            let _ = cache.store(key, &cached);
+        }
+        Ok(functions)
+    }
+
+    /* Everything the code jit_define() compiles depends on, the key of the program in the code cache */
+    fn cache_key(&self, isa: &dyn TargetIsa, instrument: Instrument) -> Vec<u8> { 
+        let mut key = format!("{} {} codegen {}\ncranelift {} jit {}\n{}\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), CODEGEN_VERSION, 
+            cranelift::VERSION, cranelift_jit::VERSION, isa.triple());
+        for flag in isa.flags().iter().chain(isa.isa_flags()) { 
+            key += &format!("{}\n", flag);
+        }
+        key += &format!("{:?} {:?} {:?} {} cells, {} ops per function, sync {} profile {}\n", 
+            self.cell_width, self.tape, self.eof, self.heap.len(), self.jit_options.max_function_ops.max(1), instrument.sync, instrument.profile);
+        key += &format!("{:?}", self.txt);
+        key.into_bytes()
+    }
+
+    /* Define the ops in range as function name in module, returns its id, statistics of the code and where each op's code starts */
+    pub(crate) fn lower<M: Module>(&self, module: &mut M, name: &str, range: Range<usize>, outlined: &[Range<usize>], clir: bool, 
+                                   instrument: Instrument) -> Result<(FuncId, CodeStats, perf::CodeOps), Error> {
+        let (id, mut ctx, mut stats) = self.prepare(module, name, Linkage::Export, range, outlined, instrument)?;
+        let phase = Instant::now();
+        if let Err(err) = module.define_function(id, &mut ctx) { 
+            let isa = module.isa().triple().to_string();
+            return Err(Error::Codegen { isa, error: err.to_string(), clif: ctx.func.display().to_string() });
+        }
+        stats.compile = phase.elapsed();
+        self.finish(module, &ctx, &mut stats, clir)?;
+        let ops = ctx.compiled_code().map(perf::code_ops).unwrap_or_default();
+        Ok((id, stats, ops))
+    }
+
+    /* Declare name in module and build and verify the ops in range as its body, ready to compile */
+    fn prepare<M: Module>(&self, module: &mut M, name: &str, linkage: Linkage, range: Range<usize>, outlined: &[Range<usize>], 
+               instrument: Instrument) -> Result<(FuncId, Context, CodeStats), Error> {
+        let sig = main_signature(module.isa());
+        let id = module.declare_function(name, linkage, &sig)
+            .map_err(|_| Error::Jit("Error declaring the entry point"))?;
+
+        /* runtime callbacks, resolved by jit::module() or the linker */
+        let mut import = |func: &mut Function, name: &str, sig: Signature| { 
+            let id = module.declare_function(name, Linkage::Import, &sig)
+                .map_err(|_| Error::Jit("Error declaring a runtime function"))?;
+            Ok(module.declare_func_in_func(id, func))
+        };
+        let phase = Instant::now();
+        let (func, loops) = self.build(sig, UserFuncName::user(0, id.as_u32()), range, outlined, instrument, &mut import)?;
+        let mut stats = CodeStats { blocks: func.layout.blocks().count(), loops, build: phase.elapsed(), ..Default::default() };
+
+        let phase = Instant::now();
+        if module.isa().flags().enable_verifier() { 
+            if let Err(errors) = verify_function(&func, module.isa()) { 
+                let isa = module.isa().triple().to_string();
+                let errors = errors.0.iter().map(|error| error.to_string()).collect();
+                return Err(Error::Verifier { isa, errors, clif: func.display().to_string() });
+            }
+        }
+        stats.verify = phase.elapsed();
+        Ok((id, Context::for_function(func), stats))
+    }
+
+    /* Complete stats from the code compiled in ctx, and list the code when clir */
+    fn finish<M: Module>(&self, module: &M, ctx: &Context, stats: &mut CodeStats, clir: bool) -> Result<(), Error> {
+        let code = ctx.compiled_code().ok_or(Error::Jit("Compiled code missing after compilation"))?;
+        stats.measure(code, module.isa());
+        if clir { 
+            /* calls are to functions declared in module */
+            let callee = |target: &FinalizedRelocTarget| match target { 
+                FinalizedRelocTarget::ExternalName(ExternalName::User(name)) => { 
+                    let id = FuncId::from_u32(ctx.func.params.user_named_funcs()[*name].index);
+                    module.declarations().get_function_decl(id).name.clone().unwrap_or_default()
+                },
+                target => target.display(Some(&ctx.func.params)),
+            };
+            println!("Compiled Code: ====================\n{}", ctx.func.display()); 
+            println!("Disassembly: ====================\n{}", disasm::listing(self, module.isa(), code, &callee));
+            println!("{}", stats);
+        }
+        Ok(())
+    }
+
+    /*
+     * Functions for the ops in range: range itself with the runs of ops it
+     * calls instead of building them, then those runs. Runs are top level in
+     * range, or in its body when range is a loop, and hold at most max_ops
+     * unless they are a single bigger loop, which is split the same way.
+     */
+    fn plan(&self, range: Range<usize>, max_ops: usize, funcs: &mut Vec<(Range<usize>, Vec<Range<usize>>)>) { 
+        let body = match self.txt.get(range.start) { 
+            Some(BFIsa::Jmp(end)) if end + 1 == range.end => range.start + 1..*end,
+            _ => range.clone(),
         };
+        if body.len() <= max_ops { 
+            funcs.push((range, Vec::new()));
+            return;
+        }
 
-        // Set up runtime interface
-        let call_conv = isa::CallConv::triple_default(isa.triple());
-        let pointer_type = isa.pointer_type(); 
-        let mut sig = Signature::new(call_conv);
-        sig.params.push(AbiParam::new(pointer_type));
-        sig.returns.push(AbiParam::new(pointer_type));
+        let mut runs: Vec<Range<usize>> = Vec::new();
+        let mut idx = body.start;
+        while idx < body.end { 
+            let end = match self.txt[idx] { 
+                BFIsa::Jmp(end) => end + 1,
+                _ => idx + 1,
+            };
+            match runs.last_mut() { 
+                Some(run) if end - run.start <= max_ops => run.end = end,
+                _ => runs.push(idx..end),
+            }
+            idx = end;
+        }
+        funcs.push((range, runs.clone()));
+        for run in runs { 
+            self.plan(run, max_ops, funcs);
+        }
+    }
 
-        let mut func = Function::with_name_signature(UserFuncName::user(0, 0), sig);
+
+    /*
+     * Build the ops in range as a function with signature sig, runtime
+     * callbacks are declared through import. The range must hold whole
+     * loops. Each range in outlined, in order, is a call to the function
+     * region_name() names instead, which is imported the same way.
+     */
+    pub(crate) fn build(&self, sig: Signature, name: UserFuncName, range: Range<usize>, outlined: &[Range<usize>], instrument: Instrument, 
+             import: &mut dyn FnMut(&mut Function, &str, Signature) -> Result<FuncRef, Error>) -> Result<(Function, usize), Error> {
+        let synced = instrument.sync;
+        let region_sig = sig.clone();
+        let call_conv = sig.call_conv;
+        let pointer_type = sig.params[0].value_type;
+        let mut func = Function::with_name_signature(name, sig);
         let mut func_ctx = FunctionBuilderContext::new();
         let mut builder = FunctionBuilder::new(&mut func, &mut func_ctx);
 
@@ -259,71 +1046,161 @@ impl ProgramState {
         builder.switch_to_block(block);
 
         let heap = builder.block_params(block)[0];  // First param to block - heap pointer
-        let zero_byte = builder.ins().iconst(I8, 0);
+        let env = builder.block_params(block)[1];
+        let ptr_slot = builder.block_params(block)[2];
+        let counters = builder.block_params(block)[3];
         let zero = builder.ins().iconst(pointer_type, 0);
-        builder.def_var(ptr, zero);
-
         let mem_flags = MemFlags::new();
-
-        let (write_sig, write_address) = { 
-            let mut write_sig = Signature::new(call_conv); 
-            write_sig.params.push(AbiParam::new(I8));
-            write_sig.returns.push(AbiParam::new(pointer_type));
-            let write_sig = builder.import_signature(write_sig); 
-
-            let write_address = write as *const () as i64;
-            let write_address = builder.ins().iconst(pointer_type, write_address); 
-            (write_sig, write_address)
+        let start_ptr = builder.ins().load(pointer_type, mem_flags, ptr_slot, 0);
+        builder.def_var(ptr, start_ptr);
+
+        /* counters[slot] += by (or 1) */
+        let len = self.txt.len();
+        let bump = |builder: &mut FunctionBuilder, slot: usize, by: Option<Value>| { 
+            if !instrument.profile { 
+                return;
+            }
+            let offset = (slot * 8) as i32;
+            let count = builder.ins().load(I64, mem_flags, counters, offset);
+            let count = match by { 
+                Some(by) => builder.ins().iadd(count, by),
+                None => builder.ins().iadd_imm(count, 1),
+            };
+            builder.ins().store(mem_flags, count, counters, offset);
+        };
+        /* counts the loop ops that found a non zero cell */
+        let bump_nonzero = |builder: &mut FunctionBuilder, slot: usize, val: Value| { 
+            if instrument.profile { 
+                let nonzero = builder.ins().icmp_imm(IntCC::NotEqual, val, 0);
+                let nonzero = builder.ins().uextend(I64, nonzero);
+                bump(builder, slot, Some(nonzero));
+            }
         };
+        if range == (0..len) { 
+            bump(&mut builder, 0, None);
+        }
 
-        let (read_sig, read_address) = { 
-            let mut read_sig = Signature::new(call_conv); 
-            read_sig.params.push(AbiParam::new(pointer_type));
-            read_sig.returns.push(AbiParam::new(pointer_type));
-            let read_sig = builder.import_signature(read_sig); 
+        /* add the iterations a loop ran to its histogram, see profile_counts() for the layout */
+        let trip_base = 2 * len + 1;
+        let loops_in = |range: Range<usize>| self.txt[range].iter().filter(|op| matches!(op, BFIsa::Jmp(_))).count();
+        let record_trip = |builder: &mut FunctionBuilder, loop_no: usize, trip: Variable| { 
+            let trips = builder.use_var(trip);
+            let base = ((trip_base + loop_no * TRIP_SLOTS) * 8) as i32;
+            let leading = builder.ins().clz(trips);
+            let bucket = builder.ins().irsub_imm(leading, 64);
+            let bucket = builder.ins().ishl_imm(bucket, 3);
+            let bucket = if pointer_type == I64 { bucket } else { builder.ins().ireduce(pointer_type, bucket) };
+            let bucket_ptr = builder.ins().iadd(counters, bucket);
+            let count = builder.ins().load(I64, mem_flags, bucket_ptr, base);
+            let count = builder.ins().iadd_imm(count, 1);
+            builder.ins().store(mem_flags, count, bucket_ptr, base);
+
+            let min_offset = base + (Trips::BUCKETS * 8) as i32;
+            let min = builder.ins().load(I64, mem_flags, counters, min_offset);
+            let min = builder.ins().umin(min, trips);
+            builder.ins().store(mem_flags, min, counters, min_offset);
+            let max = builder.ins().load(I64, mem_flags, counters, min_offset + 8);
+            let max = builder.ins().umax(max, trips);
+            builder.ins().store(mem_flags, max, counters, min_offset + 8);
+        };
 
-            let read_address = read as *const () as i64;
-            let read_address = builder.ins().iconst(pointer_type, read_address); 
-            (read_sig, read_address)
+        let cell = match self.cell_width { 
+            CellWidth::W8 => I8,
+            CellWidth::W16 => I16,
+            CellWidth::W32 => I32,
+        };
+        let tape = Tape { 
+            heap, 
+            cells: (self.heap.len() / self.cell_width.bytes()) as i64, 
+            shift: self.cell_width.bytes().trailing_zeros() as i64,
+            model: self.tape,
+            exit_block,
+            out_of_bounds: builder.ins().iconst(pointer_type, JIT_OUT_OF_BOUNDS),
+        };
+        let zero_cell = builder.ins().iconst(cell, 0);
+        let mut cache = CellCache::new(cell, tape.cells);
+
+        let mut import = |builder: &mut FunctionBuilder, name: &str, params: &[AbiParam]| { 
+            let mut sig = Signature::new(call_conv); 
+            sig.params.extend_from_slice(params);
+            sig.returns.push(AbiParam::new(pointer_type));
+            import(builder.func, name, sig)
+        };
+        let write_fn = import(&mut builder, "bf_write", &[AbiParam::new(pointer_type), AbiParam::new(I8), AbiParam::new(pointer_type)])?;
+        let read_fn = import(&mut builder, "bf_read", &[AbiParam::new(pointer_type); 3])?;
+        let sync_fn = match synced { 
+            true => Some(import(&mut builder, "bf_sync", &[AbiParam::new(pointer_type); 5])?),
+            false => None,
+        };
+        let heap_len = builder.ins().iconst(pointer_type, self.heap.len() as i64);
+
+        /* call env.sync(heap, ptr, idx) and leave through exit_block if it asks to stop */
+        let emit_sync = |builder: &mut FunctionBuilder, idx: usize| { 
+            let heap_offset = builder.use_var(ptr);
+            let idx = builder.ins().iconst(pointer_type, idx as i64);
+            let call_syncfn = builder.ins().call(sync_fn.unwrap(), &[env, heap, heap_len, heap_offset, idx]);
+            let call_retval = builder.inst_results(call_syncfn)[0];
+
+            let bb_ret = builder.create_block(); 
+            builder.ins().brif(call_retval, exit_block, &[call_retval], bb_ret, &[]); 
+            builder.seal_block(bb_ret); 
+            builder.switch_to_block(bb_ret); 
         };
 
         /* stack to hold nested '[' operators */
 
         let mut nest_stk = Vec::new();
-
-        for (idx, instr) in self.txt.iter().enumerate() { 
+        let mut loops = 0;
+        let mut loop_no = loops_in(0..range.start);    /* of the loop starting next, in the whole program */
+        let mut outlined = outlined.iter().peekable();
+        let mut resume = range.start;
+
+        for (idx, instr) in self.txt.iter().enumerate().take(range.end).skip(range.start) { 
+            if idx < resume { 
+                continue;
+            }
+            builder.set_srcloc(SourceLoc::new(idx as u32));
+            if let Some(region) = outlined.next_if(|region| region.start == idx) { 
+                /* the region reads and writes the pointer through ptr_slot */
+                let region_fn = import(&mut builder, &region_name(idx), &region_sig.params)?;
+                cache.flush(&mut builder);
+                let heap_offset = builder.use_var(ptr);
+                builder.ins().store(mem_flags, heap_offset, ptr_slot, 0);
+                let call = builder.ins().call(region_fn, &[heap, env, ptr_slot, counters]);
+                let call_retval = builder.inst_results(call)[0];
+                let heap_offset = builder.ins().load(pointer_type, mem_flags, ptr_slot, 0);
+                builder.def_var(ptr, heap_offset);
+
+                let bb_ret = builder.create_block(); 
+                builder.ins().brif(call_retval, exit_block, &[call_retval], bb_ret, &[]); 
+                builder.seal_block(bb_ret); 
+                builder.switch_to_block(bb_ret); 
+                cache.clear();
+                loop_no += loops_in(region.clone());
+                resume = region.end;
+                continue;
+            }
             match instr { 
                 BFIsa::Incr(n) => { 
-                    let n = *n as i64;
-                    let heap_offset = builder.use_var(ptr);
-                    let heap_ptr = builder.ins().iadd(heap, heap_offset);
-                    let val_at_heap_ptr = builder.ins().load(I8, mem_flags, heap_ptr, 0);
+                    /* wider cells take Incr as a signed byte */
+                    let n = if cell == I8 { *n as i64 } else { *n as i8 as i64 };
+                    let (heap_ptr, val_at_heap_ptr) = cache.current(&mut builder, &tape, ptr);
                     let val_at_heap_ptr = builder.ins().iadd_imm(val_at_heap_ptr, n);
-                    builder.ins().store(mem_flags, val_at_heap_ptr, heap_ptr, 0);
+                    cache.set(&mut builder, 0, heap_ptr, val_at_heap_ptr);
                 },
                 BFIsa::Mv(n) => { 
-                    let n = *n as i64;
                     let heap_offset = builder.use_var(ptr);
-                    let tgt_heap_offset = builder.ins().iadd_imm(heap_offset, n);
-
-                    let new_heap_offset = if n > 0  {
-                        let wrapped = builder.ins().iadd_imm( heap_offset, n - (self.heap.len() as i64)); 
-                        let cmp = builder.ins().icmp_imm(IntCC::SignedLessThan, tgt_heap_offset, self.heap.len() as i64);
-                        builder.ins().select(cmp, tgt_heap_offset, wrapped) 
-                    } else { 
-                        let wrapped = builder.ins().iadd_imm( heap_offset, n + (self.heap.len() as i64)); 
-                        let cmp = builder.ins().icmp_imm(IntCC::SignedLessThan, tgt_heap_offset, 0);
-                        builder.ins().select(cmp, wrapped, tgt_heap_offset)
-                    };
-
+                    let new_heap_offset = tape.offset_ptr(&mut builder, heap_offset, *n as i64, &cache.dirty());
                     builder.def_var(ptr, new_heap_offset);
+                    cache.moved(&mut builder, *n as i64);
                 },
                 BFIsa::Out => { 
-                    let heap_offset = builder.use_var(ptr);
-                    let heap_ptr = builder.ins().iadd(heap, heap_offset);
-                    let fn_arg_val = builder.ins().load(I8, mem_flags, heap_ptr, 0);
+                    let (_, fn_arg_val) = cache.current(&mut builder, &tape, ptr);
+                    let fn_arg_val = if cell == I8 { fn_arg_val } else { builder.ins().ireduce(I8, fn_arg_val) };
+                    cache.flush(&mut builder);
 
-                    let call_writefn = builder.ins().call_indirect(write_sig, write_address, &[fn_arg_val]);
+                    let op = builder.ins().iconst(pointer_type, idx as i64);
+                    let call_writefn = builder.ins().call(write_fn, &[env, fn_arg_val, op]);
                     let call_retval = builder.inst_results(call_writefn)[0];
 
                     let bb_ret = builder.create_block(); 
@@ -331,208 +1208,611 @@ impl ProgramState {
 
                     builder.seal_block(bb_ret); 
                     builder.switch_to_block(bb_ret); 
+                    if synced { 
+                        emit_sync(&mut builder, idx);
+                    }
                 }, 
                 BFIsa::In => { 
-                    let heap_offset = builder.use_var(ptr);
-                    let heap_ptr = builder.ins().iadd(heap, heap_offset);
-                    let call_readfn = builder.ins().call_indirect(read_sig, read_address, &[heap_ptr]);
+                    let heap_ptr = match cache.get(0) { 
+                        Some((heap_ptr, _)) => heap_ptr,
+                        None => { 
+                            let heap_offset = builder.use_var(ptr);
+                            tape.cell_addr(&mut builder, heap_offset)
+                        },
+                    };
+                    cache.flush(&mut builder);
+                    cache.forget(0);
+                    let op = builder.ins().iconst(pointer_type, idx as i64);
+                    let call_readfn = builder.ins().call(read_fn, &[env, heap_ptr, op]);
                     let call_retval = builder.inst_results(call_readfn)[0];
 
+                    let bb_read = builder.create_block(); 
+                    let bb_failed = builder.create_block(); 
+                    let bb_eof = builder.create_block(); 
                     let bb_ret = builder.create_block(); 
-                    builder.ins().brif(call_retval, exit_block, &[call_retval], bb_ret, &[]); 
+                    builder.ins().brif(call_retval, bb_failed, &[], bb_read, &[]); 
+                    builder.seal_block(bb_failed); 
+                    builder.seal_block(bb_read); 
+
+                    /* end of input is reported as JIT_EOF, anything else non null is an error */
+                    builder.switch_to_block(bb_failed); 
+                    let is_eof = builder.ins().icmp_imm(IntCC::Equal, call_retval, JIT_EOF);
+                    builder.ins().brif(is_eof, bb_eof, &[], exit_block, &[call_retval]); 
+                    builder.seal_block(bb_eof); 
+
+                    builder.switch_to_block(bb_eof); 
+                    match self.eof { 
+                        EofPolicy::Zero => { builder.ins().store(mem_flags, zero_cell, heap_ptr, 0); },
+                        EofPolicy::MinusOne => { 
+                            let all_ones = builder.ins().iconst(cell, -1);
+                            builder.ins().store(mem_flags, all_ones, heap_ptr, 0);
+                        },
+                        EofPolicy::Unchanged => (),
+                    }
+                    builder.ins().jump(bb_ret, &[]); 
+
+                    /* read() stores a single byte, widen it to the whole cell (little endian) */
+                    builder.switch_to_block(bb_read); 
+                    if cell != I8 { 
+                        let byte = builder.ins().load(I8, mem_flags, heap_ptr, 0);
+                        let val = builder.ins().uextend(cell, byte);
+                        builder.ins().store(mem_flags, val, heap_ptr, 0);
+                    }
+                    builder.ins().jump(bb_ret, &[]); 
 
                     builder.seal_block(bb_ret); 
                     builder.switch_to_block(bb_ret); 
+                    if synced { 
+                        emit_sync(&mut builder, idx);
+                    }
                 },
                 BFIsa::Jmp(_) => { 
+                    if synced { 
+                        cache.flush(&mut builder);
+                        emit_sync(&mut builder, idx);
+                    }
                     let inner_bb = builder.create_block(); 
                     let inner_bb_exit = builder.create_block(); 
+                    builder.append_block_param(inner_bb, cell);
+
+                    /* iterations of this entry, 0 if the body is skipped */
+                    let trip = Variable::new(1 + loops);
+                    if instrument.profile { 
+                        builder.declare_var(trip, I64);
+                        let none = builder.ins().iconst(I64, 0);
+                        builder.def_var(trip, none);
+                    }
 
-                    let heap_offset = builder.use_var(ptr);
-                    let heap_ptr = builder.ins().iadd(heap, heap_offset); 
-                    let val_at_heap_ptr = builder.ins().load(I8, mem_flags, heap_ptr, 0);  
-
-                    builder.ins().brif(val_at_heap_ptr, inner_bb, &[], inner_bb_exit, &[]); // goto ']' if ptr == 0
+                    let (_, val_at_heap_ptr) = cache.current(&mut builder, &tape, ptr);
+                    cache.flush(&mut builder);
+                    builder.ins().brif(val_at_heap_ptr, inner_bb, &[val_at_heap_ptr], inner_bb_exit, &[]); // goto ']' if ptr == 0
                     builder.switch_to_block(inner_bb); 
+                    let entered = builder.block_params(inner_bb)[0];
+                    cache.reset(&mut builder, &tape, ptr, entered);
+                    bump(&mut builder, idx + 1, None);
+                    if instrument.profile { 
+                        let trips = builder.use_var(trip);
+                        let trips = builder.ins().iadd_imm(trips, 1);
+                        builder.def_var(trip, trips);
+                    }
 
-                    nest_stk.push((inner_bb, inner_bb_exit)); // finish both BBlocks when popping stack
+                    nest_stk.push((inner_bb, inner_bb_exit, loop_no, trip)); // finish both BBlocks when popping stack
+                    loops += 1;
+                    loop_no += 1;
                 },
                 BFIsa::Ret(_) => {
-                    let (curr_bb, exit_bb) = match nest_stk.pop() { 
-                        Some((bb_expr, bb_exit)) => (bb_expr, bb_exit), 
-                        None => return Err(JitErr("Nesting Err in byte code ]")),
+                    if synced { 
+                        cache.flush(&mut builder);
+                        emit_sync(&mut builder, idx);
+                    }
+                    let (curr_bb, exit_bb, loop_no, trip) = match nest_stk.pop() { 
+                        Some(stk) => stk, 
+                        None => return Err(Error::Jit("Nesting Err in byte code ]")),
                     };
 
-                    let heap_offset = builder.use_var(ptr);
-                    let heap_ptr = builder.ins().iadd(heap, heap_offset); 
-                    let val_at_heap_ptr = builder.ins().load(I8, mem_flags, heap_ptr, 0);  
-
-                    builder.ins().brif(val_at_heap_ptr, curr_bb, &[], exit_bb, &[]); // goto '[' if ptr != 0
+                    let (_, val_at_heap_ptr) = cache.current(&mut builder, &tape, ptr);
+                    cache.flush(&mut builder);
+                    if instrument.profile { 
+                        /* count back edges on their own edge block */
+                        let back_bb = builder.create_block();
+                        builder.ins().brif(val_at_heap_ptr, back_bb, &[], exit_bb, &[]); // goto '[' if ptr != 0
+                        builder.seal_block(back_bb);
+                        builder.switch_to_block(back_bb);
+                        bump(&mut builder, len + 1 + idx, None);
+                        builder.ins().jump(curr_bb, &[val_at_heap_ptr]);
+                    } else { 
+                        builder.ins().brif(val_at_heap_ptr, curr_bb, &[val_at_heap_ptr], exit_bb, &[]); // goto '[' if ptr != 0
+                    }
                     builder.seal_block(curr_bb);
                     builder.seal_block(exit_bb);
                     builder.switch_to_block(exit_bb); 
+                    cache.reset(&mut builder, &tape, ptr, zero_cell);
+                    bump(&mut builder, idx + 1, None);
+                    if instrument.profile { 
+                        record_trip(&mut builder, loop_no, trip);
+                    }
                 },
                 BFIsa::LoopSetZero => {
-                    let heap_offset = builder.use_var(ptr);
-                    let heap_ptr = builder.ins().iadd(heap, heap_offset); 
-                    builder.ins().store(mem_flags, zero_byte, heap_ptr, 0);
+                    let (heap_ptr, val_at_heap_ptr) = cache.current(&mut builder, &tape, ptr);
+                    bump_nonzero(&mut builder, len + 1 + idx, val_at_heap_ptr);
+                    cache.set(&mut builder, 0, heap_ptr, zero_cell);
                 }, 
+                BFIsa::LoopMvData(n) if tape.model == TapeModel::Wrap => { 
+                    /* no branches, so both cells stay cached */
+                    let (rhs_ptr, rhs_val) = cache.current(&mut builder, &tape, ptr);
+                    bump_nonzero(&mut builder, len + 1 + idx, rhs_val);
+                    let (lhs_ptr, lhs_val) = match cache.get(*n as i64) { 
+                        Some(cached) => cached,
+                        None => { 
+                            let heap_offset = builder.use_var(ptr);
+                            let tgt_heap_offset = tape.offset_ptr(&mut builder, heap_offset, *n as i64, &[]);
+                            let lhs_ptr = tape.cell_addr(&mut builder, tgt_heap_offset); 
+                            (lhs_ptr, builder.ins().load(cell, mem_flags, lhs_ptr, 0))
+                        },
+                    };
+                    let sum = builder.ins().iadd(lhs_val, rhs_val);
+                    cache.set(&mut builder, *n as i64, lhs_ptr, sum);
+                    cache.set(&mut builder, 0, rhs_ptr, zero_cell);
+                },
                 BFIsa::LoopMvData(n) => {
-                    let n = *n as i64; 
+                    cache.flush(&mut builder);
+                    cache.clear();
                     let heap_offset = builder.use_var(ptr);
-                    let tgt_heap_offset = builder.ins().iadd_imm(heap_offset, n); 
-
-                    let tgt_heap_offset = if n > 0 {
-                        let wrapped = builder.ins().iadd_imm(heap_offset, n - (self.heap.len() as i64));
-                        let cmp = builder.ins().icmp_imm(IntCC::SignedLessThan, tgt_heap_offset, self.heap.len() as i64);
-                        builder.ins().select(cmp, tgt_heap_offset, wrapped) 
-                    } else { 
-                        let wrapped = builder.ins().iadd_imm(heap_offset, n + (self.heap.len()as i64));
-                        let cmp = builder.ins().icmp_imm(IntCC::SignedLessThan, tgt_heap_offset, 0);
-                        builder.ins().select(cmp, wrapped, tgt_heap_offset)
+                    let rhs_ptr = tape.cell_addr(&mut builder, heap_offset); 
+                    let rhs_val = builder.ins().load(cell, mem_flags, rhs_ptr, 0); 
+                    bump_nonzero(&mut builder, len + 1 + idx, rhs_val);
+
+                    /* a bounded tape may only fault if the loop body would have run */
+                    let done_bb = match tape.model { 
+                        TapeModel::Wrap => None, 
+                        TapeModel::Bounded => { 
+                            let body_bb = builder.create_block();
+                            let done_bb = builder.create_block();
+                            builder.ins().brif(rhs_val, body_bb, &[], done_bb, &[]);
+                            builder.seal_block(body_bb);
+                            builder.switch_to_block(body_bb);
+                            Some(done_bb)
+                        },
                     };
 
-                    let rhs_ptr = builder.ins().iadd(heap, heap_offset); 
-                    let rhs_val = builder.ins().load(I8, mem_flags, rhs_ptr, 0); 
-
-                    let lhs_ptr = builder.ins().iadd(heap, tgt_heap_offset); 
-                    let lhs_val = builder.ins().load(I8, mem_flags, lhs_ptr, 0); 
+                    let tgt_heap_offset = tape.offset_ptr(&mut builder, heap_offset, *n as i64, &[]);
+                    let lhs_ptr = tape.cell_addr(&mut builder, tgt_heap_offset); 
+                    let lhs_val = builder.ins().load(cell, mem_flags, lhs_ptr, 0); 
 
                     let sum = builder.ins().iadd(lhs_val, rhs_val);
                     builder.ins().store(mem_flags, sum, lhs_ptr, 0);
-                    builder.ins().store(mem_flags, zero_byte, rhs_ptr, 0);
+                    builder.ins().store(mem_flags, zero_cell, rhs_ptr, 0);
+
+                    if let Some(done_bb) = done_bb { 
+                        builder.ins().jump(done_bb, &[]);
+                        builder.seal_block(done_bb);
+                        builder.switch_to_block(done_bb);
+                    }
                 },
                 BFIsa::LoopMvPtr(n) => { 
-                    let n = *n as i64; 
                     let loop_bb = builder.create_block(); 
                     let loop_bb_exit = builder.create_block(); 
 
-                    /* Load from ptr variable */
-                    let heap_offset = builder.use_var(ptr);
-                    let heap_ptr = builder.ins().iadd(heap, heap_offset); 
-                    let val_at_heap_ptr = builder.ins().load(I8, mem_flags, heap_ptr, 0);  
+                    let (_, val_at_heap_ptr) = cache.current(&mut builder, &tape, ptr);
+                    cache.flush(&mut builder);
+                    bump_nonzero(&mut builder, len + 1 + idx, val_at_heap_ptr);
                     builder.ins().brif(val_at_heap_ptr, loop_bb, &[], loop_bb_exit, &[]); // goto ']' if ptr == 0
 
                     builder.switch_to_block(loop_bb); 
                     /* Load from heap-ptr variable each time due to current BB updating it */
                     let heap_offset = builder.use_var(ptr);
-                    let heap_ptr = builder.ins().iadd(heap, heap_offset); 
-                    let tgt_heap_offset = builder.ins().iadd_imm(heap_offset, n); 
-                    let tgt_heap_offset = if n > 0 { 
-                        let wrapped = builder.ins().iadd_imm(heap_offset, n - (self.heap.len() as i64));
-                        let cmp = builder.ins().icmp_imm(IntCC::SignedLessThan, tgt_heap_offset, self.heap.len() as i64);
-                        builder.ins().select(cmp, tgt_heap_offset, wrapped)
-                    } else { 
-                        let wrapped = builder.ins().iadd_imm(heap_offset, n + (self.heap.len()as i64));
-                        let cmp = builder.ins().icmp_imm(IntCC::SignedLessThan, tgt_heap_offset, 0);
-                        builder.ins().select(cmp, wrapped, tgt_heap_offset)
-                    };
-                    let loop_data_ptr = builder.ins().iadd(heap, tgt_heap_offset); 
+                    let tgt_heap_offset = tape.offset_ptr(&mut builder, heap_offset, *n as i64, &[]);
+                    let loop_data_ptr = tape.cell_addr(&mut builder, tgt_heap_offset); 
                     builder.def_var(ptr, tgt_heap_offset);
-                    let loop_data_val = builder.ins().load(I8, mem_flags, loop_data_ptr, 0); 
+                    let loop_data_val = builder.ins().load(cell, mem_flags, loop_data_ptr, 0); 
                     builder.ins().brif(loop_data_val, loop_bb, &[], loop_bb_exit, &[]); // goto ']' if ptr == 0
                     builder.seal_block(loop_bb);
                     builder.seal_block(loop_bb_exit);
 
                     builder.switch_to_block(loop_bb_exit); 
+                    cache.reset(&mut builder, &tape, ptr, zero_cell);
                 }, 
             }
         }
 
         // Post processing
-        builder.ins().return_(&[zero]);
+        builder.set_srcloc(SourceLoc::default());
+        cache.flush(&mut builder);
+        builder.ins().jump(exit_block, &[zero]);
         builder.switch_to_block(exit_block); 
         builder.seal_block(exit_block); 
 
         let result = builder.block_params(exit_block)[0];
+        let end_ptr = builder.use_var(ptr);
+        builder.ins().store(mem_flags, end_ptr, ptr_slot, 0);
         builder.ins().return_(&[result]); 
         builder.finalize(); 
-        let verified = verify_function(&func, &*isa); 
-        if let Err(errors) = verified { 
-            panic!("error message = {}", errors); 
+        Ok((func, loops))
+    }
+
+
+    pub fn jit_exec(&mut self, _clir: bool) -> Result<(i32, Duration), Error> {
+        let code = match self.jit.as_ref().and_then(|jit| jit.function(jit::MAIN)) {
+            Some(code) => code,
+            None => return Err(Error::Jit("No compiled code, call jit_compile() first")),
         }; 
 
-        let mut ctx = Context::for_function(func); 
-        let mut ctrl_plane =control::ControlPlane::default();
-
-        let code = match ctx.compile(&*isa, &mut ctrl_plane) { 
-            Ok(cc) => cc,
-            Err(err) => { 
-                println!("Error Compiling code = {:?}", err); 
-                if clir { 
-                    println!("Compiled Code: ====================\n{}", ctx.func.display()); 
-                } 
-                std::process::exit(-1);
+        let counters = match self.jit_counters.as_mut() { 
+            Some(counters) => { 
+                counters.fill(0);
+                let trip_base = 2 * self.txt.len() + 1;
+                for min in counters[trip_base..].chunks_exact_mut(TRIP_SLOTS) { 
+                    min[Trips::BUCKETS] = u64::MAX;
+                }
+                counters.as_mut_ptr()
             },
+            None => std::ptr::null_mut(),
         };
 
-        let code = code.buffer.data().to_vec();
-        if clir { 
-            println!("Compiled Code: ====================\n{}", ctx.func.display()); 
+        let bm_start = Instant::now(); 
+        let error = self.call(code, counters);
+        let elapsed = bm_start.elapsed();
+
+        if let Some(counters) = &self.jit_counters { 
+            self.profile = profile_counts(&self.txt, counters);
         }
 
-        self.jit_txt = Some(code);  // package code 
+        /* a fault is reported over a failed flush, it says where the run stopped */
+        self.returned(error)?;
+        self.env.output.flush().map_err(|error| Error::Io { at: None, error })?;
+        Ok((0, elapsed))
+    }
 
-        if clir { 
-            println!("Compiled code buffer = {:?}", self.jit_txt);
+    /* What generated code returned as an error, the pointer and tape are left as they were */
+    fn returned(&self, fault: *mut Fault) -> Result<(), Error> { 
+        match fault as i64 { 
+            0 => Ok(()),
+            JIT_OUT_OF_BOUNDS => Err(Error::OutOfBounds { at: None }),
+            _ => { 
+                let Fault { idx, error } = *unsafe { Box::from_raw(fault) };
+                let at = Some(self.location(idx));
+                Err(error.map_or(Error::Cancelled { at: at.clone() }, |error| Error::Io { at, error }))
+            },
         }
+    }
 
-        Ok(0)
+}
+
+#[derive(Clone, Copy, Default)]
+struct Instrument { 
+    sync: bool,     /* call env.sync at loop tests and after I/O */
+    profile: bool,  /* count block entries, loop back edges and non zero loop tests */
+}
+
+/* histogram buckets, then the smallest and largest trip count */
+const TRIP_SLOTS: usize = Trips::BUCKETS + 2;
+
+/*
+ * Profiled code keeps 2 * len + 1 counters: counters[i] counts entries to the
+ * straight-line run of ops starting at i (i = 0 or just after a '[' or ']')
+ * and counters[len + 1 + i] counts back edges of the ']' at i or non zero
+ * tests of the folded loop at i. Every op runs as often as the run it is in.
+ * They are followed by TRIP_SLOTS counters for each '[', in program order.
+ */
+fn profile_counts(txt: &[BFIsa], counters: &[u64]) -> profile::Profile { 
+    let len = txt.len();
+    let mut prof = profile::Profile::new(len);
+    let ops = &mut prof.ops;
+    let mut block = counters[0];
+
+    for (idx, op) in txt.iter().enumerate() { 
+        if idx > 0 && matches!(txt[idx - 1], BFIsa::Jmp(_) | BFIsa::Ret(_)) { 
+            block = counters[idx];
+        }
+        ops.exec[idx] = block;
+        match op { 
+            BFIsa::Ret(start) => { 
+                let back = counters[len + 1 + idx];
+                ops.nonzero[idx] = back;
+                ops.nonzero[*start] = counters[start + 1] - back;
+            },
+            BFIsa::LoopSetZero | BFIsa::LoopMvData(_) | BFIsa::LoopMvPtr(_) => { 
+                ops.nonzero[idx] = counters[len + 1 + idx];
+            },
+            _ => (),
+        }
+    }
+
+    let loops = txt.iter().enumerate().filter_map(|(idx, op)| match op { 
+        BFIsa::Jmp(end) => Some((idx, *end)),
+        _ => None,
+    });
+    for ((start, end), slots) in loops.zip(counters[2 * len + 1..].chunks_exact(TRIP_SLOTS)) { 
+        let buckets = &slots[..Trips::BUCKETS];
+        if buckets.iter().any(|count| *count > 0) { 
+            prof.trips[start] = Trips { 
+                buckets: buckets.to_vec(),
+                min: slots[Trips::BUCKETS],
+                max: slots[Trips::BUCKETS + 1],
+                total: prof.ops.exec[end],
+            };
+        }
+    }
+    prof
+}
+
+fn nesting(src: &[u8], unmatched: char, pos: usize) -> Error { 
+    let (line, col) = SourceMap::new(src).line_col(pos);
+    Error::Nesting { unmatched, pos, line, col }
+}
+
+/* function name of a loop compiled by interpret_tiered() */
+fn loop_name(start: usize) -> String { 
+    format!("bf_loop_{}", start)
+}
+
+fn region_name(start: usize) -> String { 
+    format!("bf_region_{}", start)
+}
+
+/* version of the code build() generates and of its calling convention, bump it with either so cached code is not reused */
+const CODEGEN_VERSION: u32 = 1;
+
+/* returned by generated code when the pointer leaves a bounded tape, never a valid Box<Fault> */
+const JIT_OUT_OF_BOUNDS: i64 = 1;
+
+/* returned by read() at the end of input, the generated code then applies the EOF policy */
+const JIT_EOF: i64 = 2;
+
+/* bf_main(heap, env, ptr slot, counters) -> error */
+pub(crate) fn main_signature(isa: &dyn TargetIsa) -> Signature { 
+    let pointer_type = isa.pointer_type(); 
+    let mut sig = Signature::new(isa.default_call_conv());
+    sig.params.push(AbiParam::new(pointer_type));   // heap
+    sig.params.push(AbiParam::new(pointer_type));   // env
+    sig.params.push(AbiParam::new(pointer_type));   // ptr slot, read on entry and written on exit
+    sig.params.push(AbiParam::new(pointer_type));   // profile counters, see profile_counts()
+    sig.returns.push(AbiParam::new(pointer_type));
+    sig
+}
+
+/* The host ISA, pic for code that is linked into executables */
+pub fn host_isa(pic: bool) -> Result<OwnedTargetIsa, Error> { 
+    make_isa(Triple::host(), &JitOptions::default(), pic)
+}
+
+/*
+ * Any ISA Cranelift supports, from "<triple>[,<isa flag>[=<value>]...]". Flags
+ * are Cranelift's names for CPU features and presets, e.g.
+ * "x86_64-unknown-linux-gnu,has_avx2,has_bmi2" or "x86_64-unknown-linux-gnu,has_sse41=false".
+ */
+pub fn target_isa(target: &str, pic: bool) -> Result<OwnedTargetIsa, Error> { 
+    let mut parts = target.split(',');
+    let triple = parts.next().unwrap_or_default();
+    let triple = triple.parse::<Triple>()
+        .map_err(|err| Error::Isa { triple: triple.to_string(), error: err.to_string() })?;
+    let isa_flags = parts.map(parse_isa_flag).collect();
+    make_isa(triple, &JitOptions { isa_flags, ..Default::default() }, pic)
+}
+
+/* "flag" or "flag=value" as in target_isa() */
+pub fn parse_isa_flag(flag: &str) -> (String, String) { 
+    let (name, value) = flag.split_once('=').unwrap_or((flag, ""));
+    (name.to_string(), value.to_string())
+}
+
+fn make_isa(triple: Triple, opts: &JitOptions, pic: bool) -> Result<OwnedTargetIsa, Error> { 
+    let isa_err = |error: String| Error::Isa { triple: triple.to_string(), error };
+    let bool_str = |on: bool| if on { "true" } else { "false" };
+    let mut builder = settings::builder();
+    for (name, value) in [
+        ("opt_level", opts.opt_level.to_string().as_str()),
+        ("regalloc_algorithm", opts.regalloc.to_string().as_str()),
+        ("enable_verifier", bool_str(opts.verify)),
+        ("preserve_frame_pointers", bool_str(opts.frame_pointers)),
+        ("enable_probestack", bool_str(opts.probestack)),
+        ("probestack_strategy", "inline"),
+        ("is_pic", bool_str(pic)),
+    ] { 
+        builder.set(name, value).map_err(|err| isa_err(format!("{} : {}", name, err)))?;
+    }
+
+    let flags = settings::Flags::new(builder); 
+    let mut isa_builder = isa::lookup(triple.clone()).map_err(|err| isa_err(err.to_string()))?;
+    for (name, value) in opts.isa_flags.iter() { 
+        match value.as_str() { 
+            "" => isa_builder.enable(name),
+            value => isa_builder.set(name, value),
+        }.map_err(|err| isa_err(format!("{} : {}", name, err)))?;
     }
+    isa_builder.finish(flags).map_err(|err| isa_err(err.to_string()))
+}
+
+/* How generated code addresses and moves across the tape */
+struct Tape { 
+    heap: Value,
+    cells: i64,
+    shift: i64,
+    model: TapeModel,
+    exit_block: Block,
+    out_of_bounds: Value,
+}
 
+impl Tape { 
+    fn cell_addr(&self, builder: &mut FunctionBuilder, offset: Value) -> Value { 
+        let offset = if self.shift == 0 { offset } else { builder.ins().ishl_imm(offset, self.shift) };
+        builder.ins().iadd(self.heap, offset)
+    }
 
-    pub fn jit_exec(&mut self, clir: bool) -> Result<(i32, Duration), JitErr> {
-        let code = match &self.jit_txt {
-            Some(code_txt) => code_txt,
-            None => { 
-                return Ok((0, Duration::ZERO)); 
+    /* offset + n on the tape, a bounded tape stores the (address, value) pairs in spill before faulting */
+    fn offset_ptr(&self, builder: &mut FunctionBuilder, offset: Value, n: i64, spill: &[(Value, Value)]) -> Value { 
+        match self.model { 
+            TapeModel::Wrap => { 
+                /* offset is on the tape, so once n is reduced the move wraps at most once */
+                let n = n.rem_euclid(self.cells);
+                if n == 0 { 
+                    return offset;
+                }
+                let tgt_heap_offset = builder.ins().iadd_imm(offset, n);
+                let wrapped = builder.ins().iadd_imm(offset, n - self.cells); 
+                let cmp = builder.ins().icmp_imm(IntCC::SignedLessThan, tgt_heap_offset, self.cells);
+                builder.ins().select(cmp, tgt_heap_offset, wrapped) 
             },
-        }; 
+            TapeModel::Bounded => { 
+                let tgt_heap_offset = builder.ins().iadd_imm(offset, n);
+                /* negative offsets compare as huge unsigned values */
+                let fault = builder.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, tgt_heap_offset, self.cells);
+                let bb_ok = builder.create_block();
+                if spill.is_empty() { 
+                    builder.ins().brif(fault, self.exit_block, &[self.out_of_bounds], bb_ok, &[]);
+                } else { 
+                    let bb_fault = builder.create_block();
+                    builder.set_cold_block(bb_fault);
+                    builder.ins().brif(fault, bb_fault, &[], bb_ok, &[]);
+                    builder.seal_block(bb_fault);
+                    builder.switch_to_block(bb_fault);
+                    for (addr, val) in spill { 
+                        builder.ins().store(MemFlags::new(), *val, *addr, 0);
+                    }
+                    builder.ins().jump(self.exit_block, &[self.out_of_bounds]);
+                }
+                builder.seal_block(bb_ok);
+                builder.switch_to_block(bb_ok);
+                tgt_heap_offset
+            },
+        }
+    }
+}
+
+/*
+ * Cells held in SSA values by generated code, by offset from the pointer.
+ * Values are loaded once and changes are only stored back by flush(), so a
+ * straight run of ops touches memory once per cell. Cached offsets span
+ * fewer cells than the tape, so no two of them can be the same cell.
+ */
+struct CellCache { 
+    cell: Type,
+    cells: i64,
+    cached: Vec<Cached>,
+}
 
-        let mut buff = memmap2::MmapOptions::new() 
-            .len(code.len())
-            .map_anon()
-            .unwrap();
+struct Cached { 
+    offset: i64,
+    addr: Value,
+    val: Value,
+    dirty: bool,    /* val is not stored yet */
+}
 
-        buff.copy_from_slice(code);
-        let buff = buff.make_exec().unwrap();
+impl CellCache { 
+    fn new(cell: Type, cells: i64) -> Self { 
+        CellCache { cell, cells, cached: Vec::new() }
+    }
 
-        let bm_start = Instant::now(); 
-        unsafe { 
-            let jit_fn : unsafe extern "C" fn(*mut u8) -> *mut usize = 
-                std::mem::transmute(buff.as_ptr());
-            let error = jit_fn(self.heap.as_mut_ptr());
-            return Ok((error as i32, bm_start.elapsed()));
+    fn get(&self, offset: i64) -> Option<(Value, Value)> { 
+        self.cached.iter().find(|cached| cached.offset == offset).map(|cached| (cached.addr, cached.val))
+    }
+
+    /* Address and value of the cell under the pointer, loaded if it is not cached */
+    fn current(&mut self, builder: &mut FunctionBuilder, tape: &Tape, ptr: Variable) -> (Value, Value) { 
+        if let Some(cached) = self.get(0) { 
+            return cached;
+        }
+        let heap_offset = builder.use_var(ptr);
+        let addr = tape.cell_addr(builder, heap_offset);
+        let val = builder.ins().load(self.cell, MemFlags::new(), addr, 0);
+        self.insert(builder, Cached { offset: 0, addr, val, dirty: false });
+        (addr, val)
+    }
+
+    /* Give the cell at offset a new value, stored by the next flush() */
+    fn set(&mut self, builder: &mut FunctionBuilder, offset: i64, addr: Value, val: Value) { 
+        self.insert(builder, Cached { offset, addr, val, dirty: true });
+    }
+
+    fn insert(&mut self, builder: &mut FunctionBuilder, new: Cached) { 
+        if let Some(cached) = self.cached.iter_mut().find(|cached| cached.offset == new.offset) { 
+            *cached = Cached { dirty: cached.dirty || new.dirty, ..new };
+            return;
+        }
+        let low = self.cached.iter().map(|cached| cached.offset).fold(new.offset, i64::min);
+        let high = self.cached.iter().map(|cached| cached.offset).fold(new.offset, i64::max);
+        if high - low >= self.cells { 
+            self.flush(builder);
+            self.cached.clear();
+        }
+        self.cached.push(new);
+    }
+
+    fn moved(&mut self, builder: &mut FunctionBuilder, n: i64) { 
+        if n.abs() >= self.cells { 
+            self.flush(builder);
+            self.cached.clear();
         }
-        Ok((-1, bm_start.elapsed()))
+        for cached in self.cached.iter_mut() { 
+            cached.offset -= n;
+        }
+    }
+
+    /* (address, value) of the cells that are not stored yet */
+    fn dirty(&self) -> Vec<(Value, Value)> { 
+        self.cached.iter().filter(|cached| cached.dirty).map(|cached| (cached.addr, cached.val)).collect()
+    }
+
+    /* Store the changed cells, for code that reads the tape or may leave the function */
+    fn flush(&mut self, builder: &mut FunctionBuilder) { 
+        for cached in self.cached.iter_mut().filter(|cached| cached.dirty) { 
+            builder.ins().store(MemFlags::new(), cached.val, cached.addr, 0);
+            cached.dirty = false;
+        }
+    }
+
+    fn forget(&mut self, offset: i64) { 
+        self.cached.retain(|cached| cached.offset != offset);
     }
 
+    fn clear(&mut self) { 
+        self.cached.clear();
+    }
+
+    /* Start over at a block where only the current cell is known, e.g. a loop header */
+    fn reset(&mut self, builder: &mut FunctionBuilder, tape: &Tape, ptr: Variable, val: Value) { 
+        self.cached.clear();
+        let heap_offset = builder.use_var(ptr);
+        let addr = tape.cell_addr(builder, heap_offset);
+        self.cached.push(Cached { offset: 0, addr, val, dirty: false });
+    }
+}
+
+/* Boxed and returned by the runtime callbacks on failure, idx is the op that called them */
+struct Fault { 
+    idx: usize,
+    error: Option<std::io::Error>,  /* None when env.sync asked to stop */
 }
 
-extern "C" fn write(value: u8) -> *mut std::io::Error { 
-    let mut stdout = std::io::stdout().lock(); 
-    let result = stdout.write_all(&[value]).and_then(|_| stdout.flush()); 
+fn fault(idx: usize, error: Option<std::io::Error>) -> *mut Fault { 
+    Box::into_raw(Box::new(Fault { idx, error }))
+}
 
-    match result { 
-        Err(err) => Box::into_raw(Box::new(err)),
+unsafe extern "C" fn write(env: *mut Env, value: u8, idx: usize) -> *mut Fault { 
+    let env = &mut *env;
+    match env.output.write_all(&[value]) { 
+        Err(err) => fault(idx, Some(err)),
         _ => std::ptr::null_mut(),
     }
 }
 
 
-unsafe extern "C" fn read(buf: *mut u8) -> *mut std::io::Error { 
-    let mut stdin = std::io::stdin().lock(); 
-    let mut value = 0; 
-    let err = stdin.read_exact(std::slice::from_mut(&mut value));
-    if let Err(err) = err { 
-        if err.kind() != std::io::ErrorKind::UnexpectedEof { 
-            return Box::into_raw(Box::new(err)); 
-        } 
-        value = 0; 
+unsafe extern "C" fn read(env: *mut Env, buf: *mut u8, idx: usize) -> *mut Fault { 
+    let env = &mut *env;
+    match env.input.read_exact(std::slice::from_mut(&mut *buf)) { 
+        Ok(()) => std::ptr::null_mut(),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => JIT_EOF as *mut Fault,
+        Err(err) => fault(idx, Some(err)),
     }
+}
 
-    *buf = value;
-    std::ptr::null_mut()
+unsafe extern "C" fn sync(env: *mut Env, heap: *const u8, len: usize, ptr: usize, idx: usize) -> *mut Fault { 
+    let env = &mut *env;
+    let tape = std::slice::from_raw_parts(heap, len);
+    if env.sync.as_mut().is_none_or(|hook| hook(idx, ptr, tape)) { 
+        return std::ptr::null_mut();
+    }
+    fault(idx, None)
 }
 
 
@@ -542,7 +1822,613 @@ mod tests {
 
     #[test]
     fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+        let prog = ProgramState::new(b"+++[->+<]>.", 16).unwrap();
+        assert_eq!(prog.txt, vec![BFIsa::Incr(3), BFIsa::LoopMvData(1), BFIsa::Mv(1), BFIsa::Out]);
+        assert_eq!(prog.spans, vec![0..3, 3..9, 9..10, 10..11]);
+    }
+
+    #[test]
+    fn trace_roundtrip() {
+        let mut prog = ProgramState::new(b"++\n[>+<-]", 16).unwrap();
+        let filter = trace::TraceFilter { steps: Some(1..100), src: Some(4..6) };
+        let mut tracer = Tracer::new(Vec::new(), filter).unwrap();
+        prog.interpret_traced(&mut tracer).unwrap();
+        assert_eq!(tracer.records, 4);
+
+        let buf = tracer.into_inner().unwrap();
+        let recs: Vec<_> = trace::TraceReader::new(&buf[..]).unwrap().map(|x| x.unwrap()).collect();
+        assert_eq!(recs[0], TraceRecord { step: 2, idx: 2, span: 4..5, ptr: 0, before: 2, after: 2 });
+        assert_eq!(recs[1], TraceRecord { step: 3, idx: 3, span: 5..6, ptr: 1, before: 0, after: 1 });
+        assert_eq!(prog.srcmap.line_col(recs[1].span.start), (2, 3));
+    }
+
+    #[test]
+    fn coverage_counts() {
+        let src = b"+[-]\n[>+]\n>[-<+>]";
+        let mut prog = ProgramState::new(src, 16).unwrap();
+        let mut counts = OpCounts::default();
+        prog.interpret_counted(&mut counts).unwrap();
+
+        assert_eq!(coverage::line_counts(&prog, &counts), vec![Some(1), Some(1), Some(1)]);
+        let loops = coverage::loops(&prog, &counts);
+        assert_eq!(loops.iter().map(|lp| (lp.src, lp.reached, lp.entered)).collect::<Vec<_>>(), 
+            vec![(1, 1, 1), (5, 1, 0), (11, 1, 0)]);
+        assert_eq!(coverage::byte_counts(&prog, &counts, src)[7..11], [Some(0), Some(0), None, Some(1)]);
+        assert_eq!(coverage::summary(&prog, &counts).branches_hit, 3);
+
+        let mut annotated = Vec::new();
+        coverage::write_annotated(&mut annotated, "t.bf", src, &prog, &counts).unwrap();
+        let annotated = String::from_utf8(annotated).unwrap();
+        assert!(annotated.contains("        1:    2:[>+]\n         :    2: ###\n"));
+        assert!(annotated.contains("        1:    3:>[-<+>]\n    !!!!!"));
+    }
+
+    #[test]
+    fn profile_loop_tree() {
+        let mut prog = ProgramState::new(b"++[>+++[>+<-]<-]", 16).unwrap();
+        let mut counts = OpCounts::default();
+        prog.interpret_counted(&mut counts).unwrap();
+        let prof = profile::Profile { ops: counts, ..Default::default() };
+
+        let tree = prof.loop_tree(&prog);
+        assert_eq!(tree.len(), 1);
+        assert_eq!((tree[0].entries, tree[0].iterations, tree[0].src.clone()), (1, 2, 2..16));
+        assert_eq!(tree[0].children[0].iterations, 6);
+        assert_eq!(tree[0].inclusive, tree[0].exclusive + tree[0].children[0].inclusive);
+        assert_eq!(prof.hot_loops(&prog, 2)[1].start, 4);
+        assert_eq!(prof.totals(&prog.txt).ret, 8);
+    }
+
+    #[test]
+    fn profile_export() {
+        let mut prog = ProgramState::new(b"++[>+++\n[>+<-]<-]", 16).unwrap();
+        prog.interpret_profiled().unwrap();
+
+        let mut folded = Vec::new();
+        prog.profile.write_folded(&mut folded, "p", &prog).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "p 1\np;1:3 11\np;1:3;2:1 32\n");
+
+        let mut json = Vec::new();
+        prog.profile.write_json(&mut json, "p", &prog).unwrap();
+        let a = profile::read_json(&json[..]).unwrap();
+        assert_eq!(a.loops[1].path, "1:3/2:1");
+        assert!(profile::diff_json(&a, &a).is_empty());
+
+        let mut b = a.clone();
+        b.loops[1].inclusive += 10;
+        let changes = profile::diff_json(&a, &b);
+        assert_eq!((changes.len(), changes[0].path.as_str(), changes[0].delta()), (1, "1:3/2:1", 10));
+    }
+
+    #[test]
+    fn tape_stats() {
+        let mut prog = ProgramState::new(b">>+[<+>-]<<<", 8).unwrap();
+        let mut stats = TapeStats::default();
+        prog.interpret_tape_stats(&mut stats).unwrap();
+
+        assert_eq!((stats.lowest, stats.highest, stats.touched()), (-1, 2, 2));
+        assert_eq!((stats.reads[1], stats.writes[1], stats.writes[2]), (1, 1, 2));
+        assert_eq!(stats.suggested_size(), 4);
+        assert_eq!(heatmap::static_range(&prog.txt), Ok(-1..=2));
+        assert_eq!(heatmap::static_range(&ProgramState::new(b"+[>]", 8).unwrap().txt), Err(1));
+    }
+
+    #[test]
+    fn jit_recompile() {
+        /* clears the cells it uses, so a second run from where the first stopped prints the same */
+        let mut prog = ProgramState::new(b"[-]>[-]<++++++++[>++++++++<-]>+.+.", 8).unwrap();
+        let out = diff::SharedBuf::default();
+        prog.env.output = Box::new(out.clone());
+        for _ in 0..2 {
+            prog.jit_compile(false).unwrap();
+            assert_eq!(prog.compiled().unwrap().functions().collect::<Vec<_>>(), vec![jit::MAIN]);
+            prog.jit_exec(false).unwrap();
+        }
+        assert_eq!(out.0.lock().unwrap().as_slice(), b"ABAB");
+    }
+
+    #[test]
+    fn cell_cache() {
+        /* cells changed since the last store must reach the tape on every way out */
+        let opts = Options { tape: TapeModel::Bounded, ..Options::new(8) };
+        for src in [&b"+>++>+++<<<<"[..], b"+>++>+++[>+++<-]<<<<", b"+>++>>+++<<[<]<", b"++>+++<[->>+<<]>>[-<+>]>>>>>>+"] {
+            let mut interp = ProgramState::with_options(src, &opts).unwrap();
+            let mut jit = ProgramState::with_options(src, &opts).unwrap();
+            let interpreted = interp.interpret().map(|_| ());
+            jit.jit_compile(false).unwrap();
+            let compiled = jit.jit_exec(false).map(|_| ());
+            assert_eq!(interpreted.is_ok(), compiled.is_ok());
+            assert_eq!((interp.ptr(), interp.tape()), (jit.ptr(), jit.tape()));
+        }
+
+        /* offsets a whole tape apart are the same cell */
+        let mut prog = ProgramState::new(b"+>>>>+<<<<+.", 4).unwrap();
+        let out = diff::SharedBuf::default();
+        prog.env.output = Box::new(out.clone());
+        prog.jit_compile(false).unwrap();
+        prog.jit_exec(false).unwrap();
+        assert_eq!(out.0.lock().unwrap().as_slice(), [3]);
+
+        /* moves of more than a tape wrap as often as the interpreter's */
+        for src in [&b"+>>>>>>>>>+++."[..], b"+<<<<<<<<<+++.", b"+>>>>>>>>>[<<<<<<<<<-]>>."] {
+            let mut interp = ProgramState::new(src, 4).unwrap();
+            let mut jit = ProgramState::new(src, 4).unwrap();
+            let interp_out = diff::SharedBuf::default();
+            let jit_out = diff::SharedBuf::default();
+            interp.env.output = Box::new(interp_out.clone());
+            jit.env.output = Box::new(jit_out.clone());
+            interp.interpret().unwrap();
+            jit.jit_compile(false).unwrap();
+            jit.jit_exec(false).unwrap();
+            assert_eq!((interp.ptr(), interp.tape()), (jit.ptr(), jit.tape()));
+            assert_eq!(*interp_out.0.lock().unwrap(), *jit_out.0.lock().unwrap());
+        }
+    }
+
+    #[test]
+    fn jit_options() {
+        let mut prog = ProgramState::new(b"++++++++[>++++++++<-]>+.+.", 8).unwrap();
+        let out = diff::SharedBuf::default();
+        prog.env.output = Box::new(out.clone());
+        prog.jit_options = JitOptions {
+            opt_level: settings::OptLevel::None,
+            regalloc: settings::RegallocAlgorithm::SinglePass,
+            verify: false,
+            frame_pointers: true,
+            probestack: true,
+            ..Default::default()
+        };
+        prog.jit_compile(false).unwrap();
+        prog.jit_exec(false).unwrap();
+        assert_eq!(out.0.lock().unwrap().as_slice(), b"AB");
+
+        prog.jit_options.isa_flags = vec![parse_isa_flag("has_quantum")];
+        assert!(matches!(prog.jit_compile(false), Err(Error::Isa { .. })));
+    }
+
+    #[test]
+    fn tiered() {
+        let src = b"++++++++[>++++++++<-]>+.<+++[>>++++[>++++<-]<<-]>>>.<<<,[.,]";
+        let run = |tiering: Option<&mut Tiering>| {
+            let mut prog = ProgramState::new(src, 16).unwrap();
+            let out = diff::SharedBuf::default();
+            prog.env.output = Box::new(out.clone());
+            prog.env.input = Box::new(&b"tiered"[..]);
+            match tiering {
+                Some(tiering) => prog.interpret_tiered(tiering).unwrap(),
+                None => prog.interpret().unwrap(),
+            };
+            let out = out.0.lock().unwrap().clone();
+            (out, prog.tape().to_vec(), prog.ptr())
+        };
+
+        /* the inner loop at 15 gets hot while the outer one at 12 is still interpreted, then the outer one does and calls it */
+        let mut tiering = Tiering::new(2);
+        assert_eq!(run(Some(&mut tiering)), run(None));
+        assert_eq!(tiering.compiled, vec![1, 15, 12, 28]);
+
+        let opts = Options { tape: TapeModel::Bounded, ..Options::new(8) };
+        let mut prog = ProgramState::with_options(b"+[>+]", &opts).unwrap();
+        assert!(matches!(prog.interpret_tiered(&mut Tiering::new(2)), Err(Error::OutOfBounds { at: None })));
+    }
+
+    #[test]
+    fn errors() {
+        let mut prog = ProgramState::new(b"+.", 8).unwrap();
+        assert!(matches!(prog.jit_exec(false), Err(Error::Jit(_))));
+
+        let err = Error::Verifier { 
+            isa: "x86_64-unknown-linux-gnu".to_string(),
+            errors: vec!["inst3 (v2 = iadd v0, v1): arg 1 has type i8, expected i64".to_string()],
+            clif: "function u0:0() system_v {}".to_string(),
+        };
+        assert_eq!(err.to_string(), "Generated code for x86_64-unknown-linux-gnu failed verification with 1 errors\n  inst3 (v2 = iadd v0, v1): arg 1 has type i8, expected i64");
+        assert_eq!(err.clif(), Some("function u0:0() system_v {}"));
+        assert_eq!(Error::Jit("Error finalizing compiled code").clif(), None);
+
+        /* the failing '.' and the tape as it was when it failed */
+        struct Full;
+        impl Write for Full { 
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> { Err(std::io::ErrorKind::StorageFull.into()) }
+            fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+        }
+        let mut prog = ProgramState::new(b"++>\n+++.>.", 8).unwrap();
+        prog.env.output = Box::new(Full);
+        prog.jit_compile(false).unwrap();
+        match prog.jit_exec(false) { 
+            Err(Error::Io { at: Some(at), error }) => { 
+                assert_eq!((prog.txt[at.idx], at.span, at.line, at.col), (BFIsa::Out, 7..8, 2, 4));
+                assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+            },
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+        assert_eq!((prog.ptr(), &prog.tape()[..2]), (1, &[2, 3][..]));
+
+        /* output that only fails when flushed at the end */
+        struct Unflushable;
+        impl Write for Unflushable { 
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { Ok(buf.len()) }
+            fn flush(&mut self) -> std::io::Result<()> { Err(std::io::ErrorKind::BrokenPipe.into()) }
+        }
+        let mut prog = ProgramState::new(b"+.", 8).unwrap();
+        prog.env.output = Box::new(Unflushable);
+        prog.jit_compile(false).unwrap();
+        assert!(matches!(prog.jit_exec(false), Err(Error::Io { at: None, error }) if error.kind() == std::io::ErrorKind::BrokenPipe));
+        /* a failed write is reported with its op even though the flush after it fails too */
+        struct Broken;
+        impl Write for Broken { 
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> { Err(std::io::ErrorKind::StorageFull.into()) }
+            fn flush(&mut self) -> std::io::Result<()> { Err(std::io::ErrorKind::BrokenPipe.into()) }
+        }
+        let mut prog = ProgramState::new(b"+.", 8).unwrap();
+        prog.env.output = Box::new(Broken);
+        prog.jit_compile(false).unwrap();
+        assert!(matches!(prog.jit_exec(false), Err(Error::Io { at: Some(Location { idx: 1, .. }), error }) if error.kind() == std::io::ErrorKind::StorageFull));
+
+        let err = ProgramState::new(b"+[\n>[-]", 8).err().unwrap();
+        assert_eq!(err.to_string(), "Unmatched '[' at 1:2");
+
+        let opts = Options { tape: TapeModel::Bounded, ..Options::new(8) };
+        let mut prog = ProgramState::with_options(b"+\n[>+]", &opts).unwrap();
+        assert_eq!(prog.interpret().unwrap_err().to_string(), "Tape pointer out of bounds at 2:2 (IR #2)");
+
+        /* fuel runs out at the op that would have gone over it, cancel stops at the next ']' */
+        let mut prog = ProgramState::new(b"+[]", 8).unwrap();
+        prog.set_fuel(Some(10));
+        let err = prog.interpret().unwrap_err();
+        assert_eq!((err.location().map(|at| at.idx), prog.fuel()), (Some(2), Some(0)));
+        let mut prog = ProgramState::new(b"+[]", 8).unwrap();
+        prog.cancel = Some(Arc::new(AtomicBool::new(true)));
+        assert!(matches!(prog.interpret(), Err(Error::Cancelled { at: Some(Location { idx: 2, .. }) })));
+
+        /* a folded [>] on a tape without zeros is stopped by both */
+        let src = b"+>+>+>+>+>+>+>+[>]";
+        let mut prog = ProgramState::new(src, 8).unwrap();
+        prog.set_fuel(Some(100));
+        assert!(matches!(prog.interpret(), Err(Error::OutOfFuel { .. })));
+        assert_eq!(prog.fuel(), Some(0));
+        let mut prog = ProgramState::new(src, 8).unwrap();
+        prog.set_fuel(Some(100));
+        assert!(matches!(prog.interpret_tape_stats(&mut TapeStats::new(8)), Err(Error::OutOfFuel { .. })));
+        let mut prog = ProgramState::new(src, 8).unwrap();
+        prog.cancel = Some(Arc::new(AtomicBool::new(true)));
+        assert!(matches!(prog.interpret(), Err(Error::Cancelled { .. })));
+    }
+
+    #[test]
+    fn eof_policy() {
+        for (eof, expect) in [(EofPolicy::Zero, 1u8), (EofPolicy::MinusOne, 0), (EofPolicy::Unchanged, 6)] {
+            let opts = Options { eof, ..Options::new(8) };
+            let src = b"+++++,+";
+            let mut interp = ProgramState::with_options(src, &opts).unwrap();
+            interp.env.input = Box::new(std::io::empty());
+            interp.interpret().unwrap();
+            let mut jit = ProgramState::with_options(src, &opts).unwrap();
+            jit.env.input = Box::new(std::io::empty());
+            jit.jit_compile(false).unwrap();
+            jit.jit_exec(false).unwrap();
+            assert_eq!((interp.tape()[0], jit.tape()[0]), (expect, expect));
+        }
+    }
+
+    #[test]
+    fn aot_object() {
+        let prog = ProgramState::new(b",[.,]", 64).unwrap();
+        let object = aot::object(&prog, "cat.bf").unwrap();
+        let has = |sym: &[u8]| object.windows(sym.len()).any(|w| w == sym);
+        assert!(has(b"bf_main") && has(b"bf_tape") && has(b"bf_read") && !has(b"bf_sync"));
+    }
+
+    #[test]
+    fn cross_targets() {
+        let prog = ProgramState::new(b",[.,]", 64).unwrap();
+        for (target, machine) in [("aarch64-unknown-linux-gnu", 183u16), ("riscv64gc-unknown-linux-gnu", 243), ("s390x-unknown-linux-gnu", 22)] {
+            let object = aot::object_for(&prog, "cat.bf", target_isa(target, true).unwrap()).unwrap();
+            assert_eq!(&object[..4], b"\x7fELF");
+            let e_machine = [object[18], object[19]];
+            assert_eq!(if object[5] == 1 { u16::from_le_bytes(e_machine) } else { u16::from_be_bytes(e_machine) }, machine);
+
+            let listing = aot::disassemble(&prog, &*target_isa(target, true).unwrap()).unwrap();
+            assert!(listing.starts_with(&format!("; bf_main for {},", target)) && listing.contains("bf_read"));
+        }
+        assert!(target_isa("x86_64-unknown-linux-gnu,has_avx2,has_bmi2", false).is_ok());
+        assert!(matches!(target_isa("x86_64-unknown-linux-gnu,has_quantum", false), Err(Error::Isa { .. })));
+        assert!(matches!(target_isa("pdp11-unknown-unix", false), Err(Error::Isa { .. })));
+    }
+
+    #[test]
+    fn code_stats() {
+        let mut prog = ProgramState::new(b"+[>+\n[-]<.]", 64).unwrap();
+        assert!(prog.code_stats().is_none());
+        prog.jit_compile(false).unwrap();
+        let stats = prog.code_stats().unwrap();
+        assert!(stats.code_size > 0 && stats.blocks > 2 && stats.compile > Duration::ZERO);
+        assert_eq!(stats.loops, 1);
+
+        let listing = aot::disassemble(&prog, &*host_isa(true).unwrap()).unwrap();
+        assert!(listing.contains("; 1:3 IR #2 Mv(1)") && listing.contains("IR #6 Out"));
+        assert!(listing.contains("; loops        1"));
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn elf_executable() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::{Command, Stdio};
+
+        let prog = ProgramState::new(b",[+.,]", 64).unwrap();
+        let exe = elf::executable(&prog).unwrap();
+        assert_eq!(&exe[..4], b"\x7fELF");
+        assert_eq!(exe, elf::executable(&ProgramState::new(b",[+.,]", 64).unwrap()).unwrap());
+
+        let path = std::env::temp_dir().join(format!("bf-elf-{}", std::process::id()));
+        std::fs::write(&path, &exe).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut child = Command::new(&path).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(b"HAL").unwrap();
+        let output = child.wait_with_output().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((output.status.code(), output.stdout.as_slice()), (Some(0), &b"IBM"[..]));
+    }
+
+    #[test]
+    fn transpile_c() {
+        use std::process::{Command, Stdio};
+
+        let src = b"++++++++[>++++++++<-]>+.<,[>[-]<[->+<]>.<,]>>[-]<<[>]";
+        let mut interp = ProgramState::new(src, 16).unwrap();
+        let out = diff::SharedBuf::default();
+        interp.env.output = Box::new(out.clone());
+        interp.env.input = Box::new(&b"bf"[..]);
+        interp.interpret().unwrap();
+
+        let prog = ProgramState::new(src, 16).unwrap();
+        let c = transpile::transpile(&prog, transpile::Lang::C, "test.bf", true);
+        assert!(c.contains("#define CELLS 16\n") && c.contains("while (t[p]) {  // 1:9\n"));
+
+        let dir = std::env::temp_dir().join(format!("bf-transpile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("prog.c"), c).unwrap();
+        let status = Command::new("cc").current_dir(&dir).args(["-O1", "prog.c", "-o", "prog"]).status().unwrap();
+        assert!(status.success());
+        let mut child = Command::new(dir.join("prog")).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(b"bf").unwrap();
+        let output = child.wait_with_output().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(output.stdout, *out.0.lock().unwrap());
+    }
+
+    #[test]
+    fn transpile_langs() {
+        use std::process::{Command, Stdio};
+
+        /* the generated Rust, JavaScript and Python must at least parse, skipped where the tool is missing */
+        let dir = std::env::temp_dir().join(format!("bf-langs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let checks: [(transpile::Lang, &str, &[&str]); 3] = [
+            (transpile::Lang::Rust, "rustc", &["--edition", "2021", "--crate-type", "bin", "--emit", "metadata", "-o", "prog.rmeta", "prog.rs"]),
+            (transpile::Lang::Js, "node", &["--check", "prog.js"]),
+            (transpile::Lang::Python, "python3", &["-c", "import ast; ast.parse(open('prog.py').read())"]),
+        ];
+        let sources = [&b"++++++++[>++++++++<-]>+.<,[>[-]<[->+<]>.<,]>>[-]<<[>]"[..], b"+[<>]", b"[]+[[-]>[<+>-]]"];
+        for (lang, tool, args) in checks {
+            if Command::new(tool).arg("--version").stdout(Stdio::null()).status().is_err() { 
+                continue;
+            }
+            for src in sources {
+                for peephole in [true, false] {
+                    let prog = ProgramState::with_options(src, &Options { peephole, ..Options::new(16) }).unwrap();
+                    let file = format!("prog.{}", lang.extension());
+                    std::fs::write(dir.join(&file), transpile::transpile(&prog, lang, "test.bf", true)).unwrap();
+                    let output = Command::new(tool).current_dir(&dir).args(args).output().unwrap();
+                    assert!(output.status.success(), "{:?} {}", lang, String::from_utf8_lossy(&output.stderr));
+                }
+            }
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn asm_template() {
+        use std::process::Command;
+
+        let dir = std::env::temp_dir().join(format!("bf-asm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let run = |src: &[u8], opts: &Options| {
+            let prog = ProgramState::with_options(src, opts).unwrap();
+            std::fs::write(dir.join("prog.s"), asm::asm(&prog, "test.bf", true)).unwrap();
+            assert!(Command::new("cc").current_dir(&dir).args(["prog.s", "-o", "prog"]).status().unwrap().success());
+            Command::new(dir.join("prog")).output().unwrap()
+        };
+
+        let output = run(b"++++++++[>++++++++<-]>+.+.<<[-]+++[>>>+<<<-]>>>[>]", &Options::new(100));
+        assert_eq!((output.status.code(), output.stdout.as_slice()), (Some(0), &b"AB"[..]));
+        let output = run(b"+.[>+]", &Options { cell_width: CellWidth::W16, tape: TapeModel::Bounded, ..Options::new(8) });
+        assert_eq!((output.status.code(), output.stdout.as_slice()), (Some(2), &b"\x01"[..]));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn wasm_roundtrip() {
+        let bodies = |module: &[u8]| -> Vec<Vec<u8>> {
+            wasmparser::Validator::new().validate_all(module).unwrap();
+            wasmparser::Parser::new(0).parse_all(module)
+                .filter_map(|payload| match payload.unwrap() {
+                    wasmparser::Payload::CodeSectionEntry(body) => Some(module[body.range().start as usize..body.range().end as usize].to_vec()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let src = b",[.,]++[>+++[>+<-]<-]>>[-]<[->>+<<]+[>]";
+        for (cell_width, tape) in [(CellWidth::W8, TapeModel::Wrap), (CellWidth::W16, TapeModel::Bounded), (CellWidth::W32, TapeModel::Wrap)] {
+            let opts = Options { cell_width, tape, ..Options::new(100) };
+            let prog = ProgramState::with_options(src, &opts).unwrap();
+            let binary = wasm::wasm(&prog);
+            let text = wat::parse_str(wasm::wat(&prog)).unwrap();
+            assert_eq!(bodies(&binary), bodies(&text));
+        }
+    }
+
+    #[test]
+    fn profile_engines_agree() {
+        let src = b"++[>+++[>+<-]<-]>>[-]<[->>+<<]+[>]";
+        let mut interp = ProgramState::new(src, 16).unwrap();
+        interp.interpret_profiled().unwrap();
+        let mut jit = ProgramState::new(src, 16).unwrap();
+        jit.jit_compile_profiled(false).unwrap();
+        jit.jit_exec(false).unwrap();
+
+        assert_eq!(interp.profile.ops.exec, jit.profile.ops.exec);
+        assert_eq!(interp.profile.ops.nonzero, jit.profile.ops.nonzero);
+        assert_eq!(interp.profile.trips, jit.profile.trips);
+
+        /* the inner loop runs 3 times on each of the 2 outer iterations */
+        let inner = &jit.profile.trips[4];
+        assert_eq!((inner.count(), inner.min, inner.max, inner.total), (2, 3, 3, 6));
+        assert_eq!(inner.buckets[Trips::bucket(3)], 2);
+    }
+
+    #[test]
+    fn split_functions() {
+        let src = b"++[>+++[>+<-]>>+++[>++[>+<-]<-]<<<-]>.>>>[-]+[>]<<<<+.,.";
+        let mut interp = ProgramState::new(src, 16).unwrap();
+        let out = diff::SharedBuf::default();
+        interp.env.output = Box::new(out.clone());
+        interp.env.input = Box::new(&b"!"[..]);
+        interp.interpret_profiled().unwrap();
+
+        for threads in [1, 3] {
+            let mut jit = ProgramState::new(src, 16).unwrap();
+            let jit_out = diff::SharedBuf::default();
+            jit.env.output = Box::new(jit_out.clone());
+            jit.env.input = Box::new(&b"!"[..]);
+            jit.jit_options = JitOptions { max_function_ops: 4, threads, ..Default::default() };
+            jit.jit_compile_profiled(false).unwrap();
+            jit.jit_exec(false).unwrap();
+
+            let names: Vec<_> = jit.function_stats().iter().map(|(name, _)| name.as_str()).collect();
+            assert!(names.len() > 4 && names[0] == jit::MAIN && names[1..].iter().all(|name| name.starts_with("bf_region_")));
+            assert_eq!(jit.code_stats().unwrap().loops, jit.txt.iter().filter(|op| matches!(op, BFIsa::Jmp(_))).count());
+            assert_eq!(*out.0.lock().unwrap(), *jit_out.0.lock().unwrap());
+            assert_eq!((interp.ptr(), interp.tape()), (jit.ptr(), jit.tape()));
+            assert_eq!(interp.profile.ops.exec, jit.profile.ops.exec);
+            assert_eq!(interp.profile.trips, jit.profile.trips);
+        }
+
+        /* faults in an outlined loop leave with its pointer */
+        let opts = Options { tape: TapeModel::Bounded, ..Options::new(8) };
+        let src = b"+>+>+<<[>+]";
+        let mut interp = ProgramState::with_options(src, &opts).unwrap();
+        assert!(interp.interpret().is_err());
+        let mut jit = ProgramState::with_options(src, &opts).unwrap();
+        jit.jit_options.max_function_ops = 4;
+        jit.jit_compile(false).unwrap();
+        assert!(matches!(jit.jit_exec(false), Err(Error::OutOfBounds { .. })));
+        assert_eq!(jit.function_stats().len(), 4);
+        assert_eq!((interp.ptr(), interp.tape()), (jit.ptr(), jit.tape()));
+    }
+
+    #[test]
+    fn code_cache() {
+        let dir = std::env::temp_dir().join(format!("bf-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let run = |cache: &cache::CodeCache, max_function_ops| {
+            let mut jit = ProgramState::new(b"++[>+++[>+<-]>>+++[>++[>+<-]<-]<<<-]>.>>>[-]+[>]<<<<+.,.", 16).unwrap();
+            let out = diff::SharedBuf::default();
+            jit.env.output = Box::new(out.clone());
+            jit.env.input = Box::new(&b"!"[..]);
+            jit.jit_options.max_function_ops = max_function_ops;
+            jit.code_cache = Some(cache.clone());
+            jit.jit_compile(false).unwrap();
+            jit.jit_exec(false).unwrap();
+            let out = out.0.lock().unwrap().clone();
+            (jit.code_stats().unwrap().cached, jit.function_stats().len(), out)
+        };
+        let entries = || std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+
+        let cache = cache::CodeCache::new(&dir, 1 << 20);
+        let (cached, functions, out) = run(&cache, 4);
+        assert!(!cached && functions > 4);
+        assert_eq!(run(&cache, 4), (true, functions, out.clone()));
+        assert_eq!(run(&cache, 10000), (false, 1, out.clone()));
+        assert_eq!(entries().len(), 2);
+
+        /* corrupt entries are deleted and compiled again */
+        for path in entries() {
+            let mut bytes = std::fs::read(&path).unwrap();
+            let mid = bytes.len() / 2;
+            bytes[mid] ^= 0x10;
+            std::fs::write(&path, bytes).unwrap();
+        }
+        assert_eq!(run(&cache, 4), (false, functions, out.clone()));
+        assert_eq!(run(&cache, 4), (true, functions, out.clone()));
+        assert_eq!(run(&cache, 10000), (false, 1, out.clone()));
+        assert_eq!(entries().len(), 2);
+
+        /* a store evicts the least recently used entries over the limit, here all of them */
+        assert!(!run(&cache::CodeCache::new(&dir, 0), 5).0);
+        assert!(entries().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        /* code from another lowering or another Cranelift has another key */
+        let prog = ProgramState::new(b"+", 8).unwrap();
+        let isa = make_isa(Triple::host(), &prog.jit_options, false).unwrap();
+        let key = String::from_utf8(prog.cache_key(&*isa, Instrument::default())).unwrap();
+        assert!(key.contains(&format!(" codegen {}\ncranelift {} jit {}\n", CODEGEN_VERSION, cranelift::VERSION, cranelift_jit::VERSION)));
+    }
+
+    #[test]
+    fn perf_map() {
+        let mut jit = ProgramState::new(b"++\n[>+++[>+<-]<-]>>.", 16).unwrap();
+        jit.env.output = Box::new(diff::SharedBuf::default());
+        jit.jit_options.max_function_ops = 4;
+        jit.perf = perf::PerfOptions { map: true, jitdump: true, source: "test.bf".to_string() };
+        jit.jit_compile(false).unwrap();
+        jit.jit_exec(false).unwrap();
+
+        let pid = std::process::id();
+        let map = std::fs::read_to_string(format!("/tmp/perf-{}.map", pid)).unwrap();
+        let main = format!("{:x} {:x} bf_main test.bf:1:1", jit.jit.as_ref().unwrap().function(jit::MAIN).unwrap() as usize, jit.function_stats()[0].1.code_size);
+        assert!(map.lines().any(|line| line == main));
+        assert_eq!(map.lines().count(), jit.function_stats().len());
+        assert!(map.lines().skip(1).all(|line| line.contains(" bf_region_")));
+        assert!(map.lines().any(|line| line.ends_with(" bf_region_1 test.bf:2:1")));
+
+        /* header, then line numbers and code of every function */
+        let dump = std::fs::read(format!("/tmp/jit-{}.dump", pid)).unwrap();
+        assert_eq!(dump[..4], perf::JITDUMP_MAGIC.to_le_bytes());
+        let (mut at, mut records) = (40, Vec::new());
+        while at < dump.len() {
+            records.push(u32::from_le_bytes(dump[at..at + 4].try_into().unwrap()));
+            at += u32::from_le_bytes(dump[at + 4..at + 8].try_into().unwrap()) as usize;
+        }
+        assert_eq!(at, dump.len());
+        assert_eq!(records, [2, 0].repeat(jit.function_stats().len()));
+        std::fs::remove_file(format!("/tmp/perf-{}.map", pid)).unwrap();
+        std::fs::remove_file(format!("/tmp/jit-{}.dump", pid)).unwrap();
+    }
+
+    #[test]
+    fn diff_engines() {
+        let src = b",[.-[->+<]>[<+>-]<]";
+        let interp = diff::Config { engine: diff::Engine::Interpreter, options: Options::new(64) };
+        let jit = diff::Config { engine: diff::Engine::CraneLift, options: Options::new(64) };
+        let (result, prog_a, prog_b) = diff::diff(src, b"A", &interp, &jit, None).unwrap();
+        assert!(result.divergence.is_none());
+        assert!(result.checkpoints > 60);
+        let ops = ProgramState::new(src, 64).unwrap().txt.len();
+        assert_eq!((prog_a.txt.len(), prog_b.spans.len()), (ops, ops));
+
+        let mut wide = jit.clone();
+        wide.options.cell_width = CellWidth::W16;
+        let (result, ..) = diff::diff(b"-[>+<-]", b"", &interp, &wide, None).unwrap();
+        let divergence = result.divergence.unwrap();
+        assert_eq!(divergence.kind, diff::DivergenceKind::Tape { cell: 0, a: 255, b: 65535 });
+
+        /* a side that cannot run its configuration is an error, not a divergence */
+        let wide_interp = diff::Config { engine: diff::Engine::Interpreter, options: wide.options.clone() };
+        assert!(matches!(diff::diff(b"+.", b"", &interp, &wide_interp, None), Err(Error::Unsupported(_))));
     }
 }
@@ -0,0 +1,212 @@
+/*
+ * Symbols and line numbers of JIT compiled code for Linux perf
+ *
+ * With PerfOptions::map every compiled function is appended to
+ * /tmp/perf-<pid>.map as "<start> <size> <name>" (hex addresses), the
+ * name followed by the program and the line:col of the function's first op.
+ * perf report reads the map on its own.
+ *
+ * With PerfOptions::jitdump functions are written to /tmp/jit-<pid>.dump in
+ * perf's jitdump format (tools/perf/Documentation/jitdump-specification.txt):
+ * a header, then for every function a JIT_CODE_DEBUG_INFO record mapping the
+ * code of each op to the line of the BF source it came from, followed by a
+ * JIT_CODE_LOAD record with the code bytes. The file is mapped executable
+ * once so that perf record notices it. Record with a monotonic clock and
+ * inject the dump before reporting:
+ *
+ *   perf record -k mono driver -e CraneLift --jitdump prog.bf
+ *   perf inject --jit -i perf.data -o perf.jit.data
+ *   perf report -i perf.jit.data
+ *
+ * Both files are shared by every program the process compiles.
+ */
+
+use crate::{jit, ProgramState};
+use cranelift::codegen::CompiledCode;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+use target_lexicon::{Architecture, Triple};
+
+pub const JITDUMP_MAGIC: u32 = 0x4a695444;     /* "JiTD" */
+pub const JITDUMP_VERSION: u32 = 1;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+
+#[derive(Clone, Debug, Default)]
+pub struct PerfOptions {
+    pub map: bool,          /* append compiled functions to /tmp/perf-<pid>.map */
+    pub jitdump: bool,      /* write them with their code and line numbers to /tmp/jit-<pid>.dump */
+    pub source: String,     /* the program's file, named in symbols and line numbers */
+}
+
+impl PerfOptions {
+    pub fn enabled(&self) -> bool {
+        self.map || self.jitdump
+    }
+}
+
+struct JitDump {
+    out: BufWriter<File>,
+    code_index: u64,
+}
+
+static MAP: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+static DUMP: Mutex<Option<JitDump>> = Mutex::new(None);
+
+/* code offsets where the instructions of each op start, with the op's index */
+pub(crate) type CodeOps = Vec<(u32, usize)>;
+
+pub(crate) fn code_ops(code: &CompiledCode) -> CodeOps {
+    code.buffer.get_srclocs_sorted().iter()
+        .filter(|loc| !loc.loc.is_default())
+        .map(|loc| (loc.start, loc.loc.bits() as usize))
+        .collect()
+}
+
+/* Tell perf about functions of prog finalized in compiled, each with its code size and code_ops() */
+pub(crate) fn register(prog: &ProgramState, compiled: &jit::Compiled, functions: &[(&str, usize, &CodeOps)]) -> io::Result<()> {
+    for (name, size, ops) in functions {
+        let addr = compiled.function(name).ok_or_else(|| io::Error::other(format!("{} was not compiled", name)))?;
+        /* the first op in source order, a function may start with code hoisted out of a loop */
+        let symbol = match ops.iter().map(|(_, idx)| *idx).min() {
+            Some(idx) => {
+                let (line, col) = prog.srcmap.line_col(prog.spans[idx].start);
+                format!("{} {}:{}:{}", name, prog.perf.source, line, col)
+            },
+            None => format!("{} {}", name, prog.perf.source),
+        };
+
+        if prog.perf.map {
+            let mut map = MAP.lock().unwrap();
+            if map.is_none() {
+                *map = Some(BufWriter::new(File::create(format!("/tmp/perf-{}.map", std::process::id()))?));
+            }
+            let out = map.as_mut().unwrap();
+            writeln!(out, "{:x} {:x} {}", addr as usize, size, symbol)?;
+            out.flush()?;
+        }
+
+        if prog.perf.jitdump {
+            let mut dump = DUMP.lock().unwrap();
+            if dump.is_none() {
+                *dump = Some(JitDump::create()?);
+            }
+            let lines: Vec<_> = ops.iter()
+                .map(|(offset, idx)| (addr as u64 + *offset as u64, prog.srcmap.line_col(prog.spans[*idx].start).0))
+                .collect();
+            /* finalized code stays mapped readable until compiled is dropped */
+            let code = unsafe { std::slice::from_raw_parts(addr, *size) };
+            dump.as_mut().unwrap().function(&symbol, addr as u64, code, &lines, &prog.perf.source)?;
+        }
+    }
+    Ok(())
+}
+
+impl JitDump {
+    fn create() -> io::Result<JitDump> {
+        /* mapping it needs read access */
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(format!("/tmp/jit-{}.dump", std::process::id()))?;
+        mark(&file)?;
+        let mut out = BufWriter::new(file);
+        let machine: u32 = match Triple::host().architecture {
+            Architecture::X86_64 => 62,
+            Architecture::Aarch64(_) => 183,
+            Architecture::Riscv64(_) => 243,
+            Architecture::S390x => 22,
+            _ => 0,
+        };
+        for field in [JITDUMP_MAGIC, JITDUMP_VERSION, 40, machine, 0, std::process::id()] {
+            out.write_all(&field.to_le_bytes())?;
+        }
+        out.write_all(&timestamp().to_le_bytes())?;
+        out.write_all(&0u64.to_le_bytes())?;    // flags
+        out.flush()?;
+        Ok(JitDump { out, code_index: 0 })
+    }
+
+    /* Line numbers of the code at addr, then the code itself */
+    fn function(&mut self, name: &str, addr: u64, code: &[u8], lines: &[(u64, usize)], file: &str) -> io::Result<()> {
+        let out = &mut self.out;
+        let size = 16 + 16 + lines.iter().map(|_| 16 + file.len() + 1).sum::<usize>();
+        record(out, JIT_CODE_DEBUG_INFO, size)?;
+        out.write_all(&addr.to_le_bytes())?;
+        out.write_all(&(lines.len() as u64).to_le_bytes())?;
+        for (addr, line) in lines {
+            out.write_all(&addr.to_le_bytes())?;
+            out.write_all(&(*line as u32).to_le_bytes())?;
+            out.write_all(&0u32.to_le_bytes())?;    // discriminator
+            out.write_all(file.as_bytes())?;
+            out.write_all(&[0])?;
+        }
+
+        let pid = std::process::id();
+        record(out, JIT_CODE_LOAD, 16 + 40 + name.len() + 1 + code.len())?;
+        out.write_all(&pid.to_le_bytes())?;
+        out.write_all(&pid.to_le_bytes())?;     // tid, perf only matches the pid
+        for field in [addr, addr, code.len() as u64, self.code_index] {
+            out.write_all(&field.to_le_bytes())?;
+        }
+        out.write_all(name.as_bytes())?;
+        out.write_all(&[0])?;
+        out.write_all(code)?;
+        self.code_index += 1;
+        out.flush()
+    }
+}
+
+fn record(out: &mut impl Write, id: u32, size: usize) -> io::Result<()> {
+    out.write_all(&id.to_le_bytes())?;
+    out.write_all(&(size as u32).to_le_bytes())?;
+    out.write_all(&timestamp().to_le_bytes())
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::c_void;
+
+    #[repr(C)]
+    pub struct Timespec {
+        pub sec: isize,
+        pub nsec: isize,
+    }
+
+    pub const CLOCK_MONOTONIC: i32 = 1;
+    pub const PROT_READ: i32 = 1;
+    pub const PROT_EXEC: i32 = 4;
+    pub const MAP_PRIVATE: i32 = 2;
+
+    extern "C" {
+        pub fn clock_gettime(clock: i32, time: *mut Timespec) -> i32;
+        pub fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    }
+}
+
+/* CLOCK_MONOTONIC in nanoseconds, the clock of perf record -k mono */
+#[cfg(target_os = "linux")]
+fn timestamp() -> u64 {
+    let mut time = sys::Timespec { sec: 0, nsec: 0 };
+    unsafe { sys::clock_gettime(sys::CLOCK_MONOTONIC, &mut time) };
+    time.sec as u64 * 1_000_000_000 + time.nsec as u64
+}
+
+/* perf record finds the dump through an executable mapping of it, which lives as long as the process */
+#[cfg(target_os = "linux")]
+fn mark(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let addr = unsafe { sys::mmap(std::ptr::null_mut(), 4096, sys::PROT_READ | sys::PROT_EXEC, sys::MAP_PRIVATE, file.as_raw_fd(), 0) };
+    match addr as isize {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn timestamp() -> u64 {
+    0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mark(_: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "jitdump needs Linux perf"))
+}
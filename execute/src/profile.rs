@@ -0,0 +1,371 @@
+/*
+ * Execution profile. The raw data is a count per IR op; per-kind totals,
+ * loop statistics and the loop nesting tree are derived from it and mapped
+ * back to the source through ProgramState::spans.
+ *
+ * Profiles are exported as callgrind (every loop is a function called by the
+ * enclosing loop), folded stacks keyed by the nested loop path, and JSON.
+ * Loops are named by the line:col of their '[' and nested loops by the path
+ * from the outermost loop, e.g. "3:1/5:9".
+ */
+
+use crate::{BFIsa, OpCounts, ProgramState};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::ops::{Range, RangeInclusive};
+
+pub const JSON_VERSION: u32 = 1;
+
+#[derive(Default, Debug, Clone)]
+pub struct Profile {
+    pub ops: OpCounts,
+    pub trips: Vec<Trips>,      /* indexed like ops, only '[' ops record trips */
+}
+
+/*
+ * Iterations a loop ran each time it was reached. Bucket 0 counts the times
+ * the body was skipped, bucket b > 0 the times it ran 2^(b-1) to 2^b - 1 times.
+ */
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Trips {
+    pub buckets: Vec<u64>,
+    pub min: u64,
+    pub max: u64,
+    pub total: u64,             /* iterations over all entries */
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpTotals {
+    pub arith: u64,
+    pub mv: u64,
+    pub inp: u64,
+    pub out: u64,
+    pub jmp: u64,
+    pub ret: u64,
+    pub loopsetz: u64,
+    pub loopmvptr: u64,
+    pub loopmvdata: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopProfile {
+    pub start: usize,           /* IR index of '[' */
+    pub end: usize,             /* IR index of ']' */
+    pub src: Range<usize>,      /* source bytes from '[' to ']' */
+    pub entries: u64,           /* times the body was entered from '[' */
+    pub iterations: u64,        /* times the body ran */
+    pub inclusive: u64,         /* ops executed in the loop including nested loops */
+    pub exclusive: u64,         /* ops executed in the loop excluding nested loops */
+    pub trips: Trips,
+    pub children: Vec<LoopProfile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpProfile {
+    pub idx: usize,
+    pub op: BFIsa,
+    pub span: Range<usize>,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonProfile {
+    pub version: u32,
+    pub program: String,
+    pub ops: u64,               /* ops executed */
+    pub totals: OpTotals,
+    pub loops: Vec<JsonLoop>,   /* outer loops before the loops nested in them */
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonLoop {
+    pub path: String,
+    pub line: usize,
+    pub col: usize,
+    pub depth: usize,
+    pub entries: u64,
+    pub iterations: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+/* A loop whose counts differ between two JSON profiles, None on the side that lacks it */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopChange {
+    pub path: String,
+    pub a: Option<JsonLoop>,
+    pub b: Option<JsonLoop>,
+}
+
+impl LoopChange {
+    pub fn delta(&self) -> i128 {
+        let inclusive = |lp: &Option<JsonLoop>| lp.as_ref().map_or(0, |lp| lp.inclusive as i128);
+        inclusive(&self.b) - inclusive(&self.a)
+    }
+}
+
+fn loop_name(prog: &ProgramState, lp: &LoopProfile) -> String {
+    let (line, col) = prog.srcmap.line_col(lp.src.start);
+    format!("{}:{}", line, col)
+}
+
+impl Trips {
+    pub const BUCKETS: usize = u64::BITS as usize + 1;
+
+    pub fn bucket(trips: u64) -> usize {
+        (u64::BITS - trips.leading_zeros()) as usize
+    }
+
+    pub fn bucket_range(bucket: usize) -> RangeInclusive<u64> {
+        match bucket {
+            0 => 0..=0,
+            b => 1 << (b - 1)..=u64::MAX >> (u64::BITS as usize - b),
+        }
+    }
+
+    pub fn record(&mut self, trips: u64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; Self::BUCKETS];
+            self.min = trips;
+        }
+        self.buckets[Self::bucket(trips)] += 1;
+        self.min = self.min.min(trips);
+        self.max = self.max.max(trips);
+        self.total += trips;
+    }
+
+    /* times the loop was reached */
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self.count() {
+            0 => 0.0,
+            n => self.total as f64 / n as f64,
+        }
+    }
+}
+
+impl Profile {
+    pub fn new(len: usize) -> Self {
+        Profile { ops: OpCounts::new(len), trips: vec![Trips::default(); len] }
+    }
+
+    pub fn totals(&self, txt: &[BFIsa]) -> OpTotals {
+        let mut totals = OpTotals::default();
+        for (op, count) in txt.iter().zip(self.ops.exec.iter()) {
+            let total = match op {
+                BFIsa::Incr(_) => &mut totals.arith,
+                BFIsa::Mv(_) => &mut totals.mv,
+                BFIsa::In => &mut totals.inp,
+                BFIsa::Out => &mut totals.out,
+                BFIsa::Jmp(_) => &mut totals.jmp,
+                BFIsa::Ret(_) => &mut totals.ret,
+                BFIsa::LoopSetZero => &mut totals.loopsetz,
+                BFIsa::LoopMvPtr(_) => &mut totals.loopmvptr,
+                BFIsa::LoopMvData(_) => &mut totals.loopmvdata,
+            };
+            *total += count;
+        }
+        totals
+    }
+
+    /* Outermost loops, each holding its nested loops */
+    pub fn loop_tree(&self, prog: &ProgramState) -> Vec<LoopProfile> {
+        let mut top = Vec::new();
+        let mut stack: Vec<(usize, Vec<LoopProfile>)> = Vec::new();
+
+        for (idx, op) in prog.txt.iter().enumerate() {
+            match op {
+                BFIsa::Jmp(_) => stack.push((idx, Vec::new())),
+                BFIsa::Ret(_) => {
+                    let (start, children) = stack.pop().expect("unbalanced loops in byte code");
+                    let inclusive = self.ops.exec[start..=idx].iter().sum();
+                    let nested: u64 = children.iter().map(|child: &LoopProfile| child.inclusive).sum();
+                    let lp = LoopProfile {
+                        start,
+                        end: idx,
+                        src: prog.spans[start].start..prog.spans[idx].end,
+                        entries: self.ops.nonzero[start],
+                        iterations: self.ops.exec[idx],
+                        inclusive,
+                        exclusive: inclusive - nested,
+                        trips: self.trips.get(start).cloned().unwrap_or_default(),
+                        children,
+                    };
+                    match stack.last_mut() {
+                        Some((_, siblings)) => siblings.push(lp),
+                        None => top.push(lp),
+                    }
+                },
+                _ => (),
+            }
+        }
+        top
+    }
+
+    /* All loops, hottest (most ops executed inside) first */
+    pub fn hot_loops(&self, prog: &ProgramState, top: usize) -> Vec<LoopProfile> {
+        fn flatten(loops: Vec<LoopProfile>, out: &mut Vec<LoopProfile>) {
+            for mut lp in loops {
+                flatten(std::mem::take(&mut lp.children), out);
+                out.push(lp);
+            }
+        }
+
+        let mut loops = Vec::new();
+        flatten(self.loop_tree(prog), &mut loops);
+        loops.retain(|lp| lp.inclusive > 0);
+        loops.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then(a.start.cmp(&b.start)));
+        loops.truncate(top);
+        loops
+    }
+
+    pub fn hot_ops(&self, prog: &ProgramState, top: usize) -> Vec<OpProfile> {
+        let mut ops: Vec<_> = prog.txt.iter()
+            .enumerate()
+            .filter(|(idx, _)| self.ops.exec[*idx] > 0)
+            .map(|(idx, op)| OpProfile { idx, op: *op, span: prog.spans[idx].clone(), count: self.ops.exec[idx] })
+            .collect();
+        ops.sort_by(|a, b| b.count.cmp(&a.count).then(a.idx.cmp(&b.idx)));
+        ops.truncate(top);
+        ops
+    }
+
+    /* Self cost per source line of the ops in range that are not inside one of the children */
+    fn self_lines(&self, prog: &ProgramState, range: Range<usize>, children: &[LoopProfile]) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+        let mut children = children.iter().peekable();
+        let mut idx = range.start;
+        while idx < range.end {
+            if let Some(child) = children.next_if(|child| child.start == idx) {
+                idx = child.end + 1;
+                continue;
+            }
+            *lines.entry(prog.srcmap.line_col(prog.spans[idx].start).0).or_insert(0) += self.ops.exec[idx];
+            idx += 1;
+        }
+        lines
+    }
+
+    pub fn write_callgrind<W: Write>(&self, mut out: W, path: &str, prog: &ProgramState) -> io::Result<()> {
+        writeln!(out, "# callgrind format")?;
+        writeln!(out, "version: 1")?;
+        writeln!(out, "creator: bfrs_jit")?;
+        writeln!(out, "cmd: {}", path)?;
+        writeln!(out, "positions: line")?;
+        writeln!(out, "events: Ops")?;
+        writeln!(out, "summary: {}", self.ops.exec.iter().sum::<u64>())?;
+        self.write_callgrind_fn(&mut out, path, "main", 0..prog.txt.len(), &self.loop_tree(prog), prog)
+    }
+
+    fn write_callgrind_fn<W: Write>(&self, out: &mut W, path: &str, name: &str, range: Range<usize>, children: &[LoopProfile], prog: &ProgramState) -> io::Result<()> {
+        writeln!(out)?;
+        writeln!(out, "fl={}", path)?;
+        writeln!(out, "fn={}", name)?;
+        for (line, cost) in self.self_lines(prog, range, children).into_iter().filter(|(_, cost)| *cost > 0) {
+            writeln!(out, "{} {}", line, cost)?;
+        }
+        for child in children.iter().filter(|child| child.inclusive > 0) {
+            let line = prog.srcmap.line_col(child.src.start).0;
+            writeln!(out, "cfn=loop {}", loop_name(prog, child))?;
+            writeln!(out, "calls={} {}", self.ops.exec[child.start], line)?;
+            writeln!(out, "{} {}", line, child.inclusive)?;
+        }
+        for child in children.iter().filter(|child| child.inclusive > 0) {
+            let name = format!("loop {}", loop_name(prog, child));
+            self.write_callgrind_fn(out, path, &name, child.start..child.end + 1, &child.children, prog)?;
+        }
+        Ok(())
+    }
+
+    /* One line per loop path with the ops executed in that loop and not in a nested loop */
+    pub fn write_folded<W: Write>(&self, mut out: W, name: &str, prog: &ProgramState) -> io::Result<()> {
+        fn walk<W: Write>(out: &mut W, stack: &str, loops: &[LoopProfile], prog: &ProgramState) -> io::Result<()> {
+            for lp in loops.iter().filter(|lp| lp.inclusive > 0) {
+                let stack = format!("{};{}", stack, loop_name(prog, lp));
+                if lp.exclusive > 0 {
+                    writeln!(out, "{} {}", stack, lp.exclusive)?;
+                }
+                walk(out, &stack, &lp.children, prog)?;
+            }
+            Ok(())
+        }
+
+        let tree = self.loop_tree(prog);
+        let outside = self.ops.exec.iter().sum::<u64>() - tree.iter().map(|lp| lp.inclusive).sum::<u64>();
+        if outside > 0 {
+            writeln!(out, "{} {}", name, outside)?;
+        }
+        walk(&mut out, name, &tree, prog)
+    }
+
+    pub fn to_json(&self, name: &str, prog: &ProgramState) -> JsonProfile {
+        fn walk(loops: &[LoopProfile], parent: Option<&str>, depth: usize, prog: &ProgramState, out: &mut Vec<JsonLoop>) {
+            for lp in loops {
+                let (line, col) = prog.srcmap.line_col(lp.src.start);
+                let path = match parent {
+                    Some(parent) => format!("{}/{}:{}", parent, line, col),
+                    None => format!("{}:{}", line, col),
+                };
+                out.push(JsonLoop {
+                    path: path.clone(),
+                    line,
+                    col,
+                    depth,
+                    entries: lp.entries,
+                    iterations: lp.iterations,
+                    inclusive: lp.inclusive,
+                    exclusive: lp.exclusive,
+                });
+                walk(&lp.children, Some(&path), depth + 1, prog, out);
+            }
+        }
+
+        let mut loops = Vec::new();
+        walk(&self.loop_tree(prog), None, 0, prog, &mut loops);
+        JsonProfile {
+            version: JSON_VERSION,
+            program: name.to_string(),
+            ops: self.ops.exec.iter().sum(),
+            totals: self.totals(&prog.txt),
+            loops,
+        }
+    }
+
+    pub fn write_json<W: Write>(&self, out: W, name: &str, prog: &ProgramState) -> io::Result<()> {
+        serde_json::to_writer_pretty(out, &self.to_json(name, prog))?;
+        Ok(())
+    }
+}
+
+pub fn read_json<R: Read>(input: R) -> io::Result<JsonProfile> {
+    let profile: JsonProfile = serde_json::from_reader(input)?;
+    if profile.version != JSON_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported profile version {}", profile.version)));
+    }
+    Ok(profile)
+}
+
+/* Loops whose counts differ between a and b, largest change of inclusive ops first */
+pub fn diff_json(a: &JsonProfile, b: &JsonProfile) -> Vec<LoopChange> {
+    let by_path = |profile: &JsonProfile| -> HashMap<String, JsonLoop> {
+        profile.loops.iter().map(|lp| (lp.path.clone(), lp.clone())).collect()
+    };
+    let (mut loops_a, mut loops_b) = (by_path(a), by_path(b));
+
+    /* keep the order loops appear in a, then loops only present in b */
+    let paths: Vec<String> = a.loops.iter()
+        .chain(b.loops.iter().filter(|lp| !loops_a.contains_key(&lp.path)))
+        .map(|lp| lp.path.clone())
+        .collect();
+
+    let mut changes: Vec<LoopChange> = paths.into_iter()
+        .map(|path| LoopChange { a: loops_a.remove(&path), b: loops_b.remove(&path), path })
+        .filter(|change| change.a != change.b)
+        .collect();
+    changes.sort_by_key(|change| std::cmp::Reverse(change.delta().abs()));
+    changes
+}
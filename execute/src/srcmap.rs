@@ -0,0 +1,42 @@
+/* Maps byte offsets in a BF source file to 1-based line/column positions */
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    line_starts: Vec<usize>,
+    len: usize,
+}
+
+impl SourceMap {
+    pub fn new(src: &[u8]) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(src.iter()
+                .enumerate()
+                .filter(|(_, byte)| **byte == b'\n')
+                .map(|(pos, _)| pos + 1))
+            .collect();
+
+        SourceMap { line_starts, len: src.len() }
+    }
+
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        (line + 1, offset - self.line_starts[line] + 1)
+    }
+
+    pub fn lines(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /* Byte range of a 1-based line, excluding the newline */
+    pub fn line_range(&self, line: usize) -> std::ops::Range<usize> {
+        let start = self.line_starts[line - 1];
+        let end = match self.line_starts.get(line) {
+            Some(next) => next - 1,
+            None => self.len,
+        };
+        start..end
+    }
+}
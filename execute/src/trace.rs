@@ -0,0 +1,185 @@
+/*
+ * Execution trace recording
+ *
+ * A trace file starts with the 4 byte magic "BFTR" followed by a version
+ * byte (currently 1). Each executed op that passes the filter is then
+ * written as one record of unsigned LEB128 varints:
+ *
+ *   step delta   steps since the previous record (absolute step for the first)
+ *   ir index     index of the op in ProgramState::txt
+ *   span start   first source byte the op was built from
+ *   span len     number of source bytes covered by the op
+ *   ptr          tape pointer before the op ran
+ *
+ * followed by two raw bytes: the value of the cell at ptr before and after
+ * the op ran. Records are read back with TraceReader and converted to one
+ * line of text per record with to_text().
+ */
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+
+pub const MAGIC: &[u8; 4] = b"BFTR";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub step: u64,
+    pub idx: usize,
+    pub span: Range<usize>,
+    pub ptr: usize,
+    pub before: u8,
+    pub after: u8,
+}
+
+/* Both ranges are half open; an op matches the source filter if its span overlaps it */
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub steps: Option<Range<u64>>,
+    pub src: Option<Range<usize>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, step: u64, span: &Range<usize>) -> bool {
+        let in_steps = self.steps
+            .as_ref()
+            .is_none_or(|steps| steps.contains(&step));
+        let in_src = self.src
+            .as_ref()
+            .is_none_or(|src| span.start < src.end && src.start < span.end);
+        in_steps && in_src
+    }
+}
+
+pub struct Tracer<W: Write> {
+    out: BufWriter<W>,
+    filter: TraceFilter,
+    last_step: u64,
+    pub records: u64,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, filter: TraceFilter) -> io::Result<Self> {
+        let mut out = BufWriter::new(out);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(Tracer { out, filter, last_step: 0, records: 0 })
+    }
+
+    pub fn wants(&self, step: u64, span: &Range<usize>) -> bool {
+        self.filter.matches(step, span)
+    }
+
+    pub fn record(&mut self, rec: &TraceRecord) -> io::Result<()> {
+        write_varint(&mut self.out, rec.step - self.last_step)?;
+        write_varint(&mut self.out, rec.idx as u64)?;
+        write_varint(&mut self.out, rec.span.start as u64)?;
+        write_varint(&mut self.out, rec.span.len() as u64)?;
+        write_varint(&mut self.out, rec.ptr as u64)?;
+        self.out.write_all(&[rec.before, rec.after])?;
+
+        self.last_step = rec.step;
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> io::Result<W> {
+        self.out.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+pub struct TraceReader<R: Read> {
+    input: BufReader<R>,
+    last_step: u64,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(input: R) -> io::Result<Self> {
+        let mut input = BufReader::new(input);
+        let mut header = [0u8; 5];
+        input.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a BF trace file"));
+        }
+        Ok(TraceReader { input, last_step: 0 })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        if self.input.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let step = self.last_step + read_varint(&mut self.input)?;
+        let idx = read_varint(&mut self.input)? as usize;
+        let start = read_varint(&mut self.input)? as usize;
+        let len = read_varint(&mut self.input)? as usize;
+        let ptr = read_varint(&mut self.input)? as usize;
+        let mut vals = [0u8; 2];
+        self.input.read_exact(&mut vals)?;
+
+        self.last_step = step;
+        Ok(Some(TraceRecord { step, idx, span: start..start + len, ptr, before: vals[0], after: vals[1] }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/* Convert a binary trace to text, one record per line */
+pub fn to_text<R: Read, W: Write>(input: R, out: W) -> io::Result<u64> {
+    let mut out = BufWriter::new(out);
+    let mut count = 0;
+
+    writeln!(out, "# step idx span ptr before after")?;
+    for rec in TraceReader::new(input)? {
+        let rec = rec?;
+        writeln!(out, "{} {} {}..{} {} {} {}",
+            rec.step, rec.idx, rec.span.start, rec.span.end, rec.ptr, rec.before, rec.after)?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+pub(crate) fn write_varint<W: Write>(out: &mut W, mut val: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    out.write_all(&buf[..len])
+}
+
+pub(crate) fn read_varint<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut val = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        input.read_exact(&mut byte)?;
+        if shift >= 64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "varint overflow"));
+        }
+        val |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(val);
+        }
+        shift += 7;
+    }
+}
@@ -0,0 +1,305 @@
+/*
+ * Source to source translation of the byte code into C, Rust, JavaScript
+ * (node) or Python. Tape size, cell width, tape model and EOF policy are
+ * fixed in the generated program as in aot::object(), and every op can
+ * carry a line:col comment pointing back at the BF source.
+ *
+ * Generated programs behave like aot::link() executables: output is a byte
+ * per '.', stdout is flushed before each ',', and leaving a bounded tape
+ * prints an error and exits with status 2.
+ */
+
+use crate::{BFIsa, CellWidth, EofPolicy, ProgramState, TapeModel};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lang {
+    C,
+    Rust,
+    Js,
+    Python,
+}
+
+impl Lang {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Lang::C => "c",
+            Lang::Rust => "rs",
+            Lang::Js => "js",
+            Lang::Python => "py",
+        }
+    }
+
+    fn comment(self) -> &'static str {
+        match self {
+            Lang::Python => "#",
+            _ => "//",
+        }
+    }
+}
+
+struct Emitter<'a> {
+    prog: &'a ProgramState,
+    lang: Lang,
+    cells: usize,
+    bits: usize,
+    spans: bool,
+    reads: bool,    /* program has ',' */
+    moves: bool,    /* program moves the pointer */
+}
+
+/* Source for prog in lang, name is only used in the header comment */
+pub fn transpile(prog: &ProgramState, lang: Lang, name: &str, spans: bool) -> String {
+    let em = Emitter {
+        prog,
+        lang,
+        cells: prog.heap.len() / prog.cell_width.bytes(),
+        bits: prog.cell_width.bytes() * 8,
+        spans,
+        reads: prog.txt.contains(&BFIsa::In),
+        moves: prog.txt.iter().any(|op| matches!(op, BFIsa::Mv(_) | BFIsa::LoopMvData(_) | BFIsa::LoopMvPtr(_))),
+    };
+
+    let mut out = String::new();
+    writeln!(out, "{} {}", lang.comment(), header(prog, name)).unwrap();
+    out.push_str(&em.prelude());
+    em.body(&mut out);
+    out.push_str(&em.epilogue());
+    out
+}
+
+/* what a generated program was built from, for its first comment */
+pub(crate) fn header(prog: &ProgramState, name: &str) -> String {
+    let model = match prog.tape {
+        TapeModel::Wrap => "wrapping",
+        TapeModel::Bounded => "bounded",
+    };
+    let eof = match prog.eof {
+        EofPolicy::Zero => "zero",
+        EofPolicy::MinusOne => "minus-one",
+        EofPolicy::Unchanged => "unchanged",
+    };
+    format!("Generated by bfexec from {}: {} cells of {} bits, {} tape, EOF {}",
+            name, prog.heap.len() / prog.cell_width.bytes(), prog.cell_width.bytes() * 8, model, eof)
+}
+
+impl Emitter<'_> {
+    fn bounded(&self) -> bool {
+        self.prog.tape == TapeModel::Bounded
+    }
+
+    /* value stored by ',' at the end of input */
+    fn eof_value(&self) -> &'static str {
+        match (self.prog.eof, self.lang) {
+            (EofPolicy::Zero, _) => "0",
+            (EofPolicy::MinusOne, Lang::C) => "(cell)-1",
+            (EofPolicy::MinusOne, Lang::Rust) => "Cell::MAX",
+            (EofPolicy::MinusOne, Lang::Js) => "-1",
+            (EofPolicy::MinusOne, Lang::Python) => "MASK",
+            (EofPolicy::Unchanged, _) => "cur",
+        }
+    }
+
+    fn prelude(&self) -> String {
+        let unchanged = self.prog.eof == EofPolicy::Unchanged;
+        let mut out = String::new();
+        match self.lang {
+            Lang::C => {
+                out.push_str("#include <stddef.h>\n#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n\n");
+                writeln!(out, "#define CELLS {}\ntypedef uint{}_t cell;\n\nstatic cell t[CELLS];", self.cells, self.bits).unwrap();
+                if self.reads {
+                    writeln!(out, "\nstatic cell rd({})\n{{\n    fflush(stdout);\n    int c = getchar();\n    return c == EOF ? {} : (cell)c;\n}}",
+                             if unchanged { "cell cur" } else { "void" }, self.eof_value()).unwrap();
+                }
+                if self.bounded() && self.moves {
+                    out.push_str("\nstatic size_t mv(size_t p, ptrdiff_t d)\n{\n    ptrdiff_t q = (ptrdiff_t)p + d;\n    if (q < 0 || q >= CELLS) {\n");
+                    out.push_str("        fflush(stdout);\n        fprintf(stderr, \"tape pointer out of bounds\\n\");\n        exit(2);\n    }\n    return (size_t)q;\n}\n");
+                }
+                out.push_str("\nint main(void)\n{\n    size_t p = 0;\n");
+            },
+            Lang::Rust => {
+                writeln!(out, "use std::io::{{{}Write}};\n\nconst CELLS: usize = {};\ntype Cell = u{};",
+                         if self.reads { "Read, " } else { "" }, self.cells, self.bits).unwrap();
+                if self.reads {
+                    writeln!(out, "\nfn rd(input: &mut impl Read, out: &mut impl Write{}) -> Cell {{\n    out.flush().unwrap();\n    let mut byte = [0u8];\n    match input.read(&mut byte).unwrap() {{\n        0 => {},\n        _ => Cell::from(byte[0]),\n    }}\n}}",
+                             if unchanged { ", cur: Cell" } else { "" }, self.eof_value()).unwrap();
+                }
+                if self.bounded() && self.moves {
+                    out.push_str("\nfn mv(p: usize, d: isize, out: &mut impl Write) -> usize {\n    match p.checked_add_signed(d) {\n        Some(q) if q < CELLS => q,\n        _ => {\n");
+                    out.push_str("            out.flush().unwrap();\n            eprintln!(\"tape pointer out of bounds\");\n            std::process::exit(2);\n        },\n    }\n}\n");
+                }
+                out.push_str("\nfn main() {\n");
+                if self.reads {
+                    out.push_str("    let mut input = std::io::stdin().lock();\n");
+                }
+                out.push_str("    let mut out = std::io::BufWriter::new(std::io::stdout().lock());\n    let mut t: Vec<Cell> = vec![0; CELLS];\n    let mut p: usize = 0;\n");
+            },
+            Lang::Js => {
+                writeln!(out, "'use strict';\nconst fs = require('fs');\n\nconst CELLS = {};\nconst t = new Uint{}Array(CELLS);\nconst out = [];", self.cells, self.bits).unwrap();
+                out.push_str("\nfunction flush() {\n    const buf = Uint8Array.from(out);\n    let done = 0;\n    while (done < buf.length) {\n        done += fs.writeSync(1, buf, done);\n    }\n    out.length = 0;\n}\n");
+                out.push_str("\nfunction put(byte) {\n    out.push(byte & 0xff);\n    if (out.length >= 4096) {\n        flush();\n    }\n}\n");
+                if self.reads {
+                    writeln!(out, "\nfunction rd({}) {{\n    flush();\n    const byte = new Uint8Array(1);\n    return fs.readSync(0, byte, 0, 1, null) === 1 ? byte[0] : {};\n}}",
+                             if unchanged { "cur" } else { "" }, self.eof_value()).unwrap();
+                }
+                if self.bounded() && self.moves {
+                    out.push_str("\nfunction mv(p, d) {\n    const q = p + d;\n    if (q < 0 || q >= CELLS) {\n        flush();\n");
+                    out.push_str("        process.stderr.write('tape pointer out of bounds\\n');\n        process.exit(2);\n    }\n    return q;\n}\n");
+                }
+                out.push_str("\nfunction main() {\n    let p = 0;\n");
+            },
+            Lang::Python => {
+                writeln!(out, "import sys\n\nCELLS = {}\nMASK = 0x{:x}", self.cells, u64::MAX >> (64 - self.bits)).unwrap();
+                if self.reads {
+                    writeln!(out, "\n\ndef rd({}):\n    sys.stdout.buffer.flush()\n    c = sys.stdin.buffer.read(1)\n    return c[0] if c else {}",
+                             if unchanged { "cur" } else { "" }, self.eof_value()).unwrap();
+                }
+                if self.bounded() && self.moves {
+                    out.push_str("\n\ndef mv(p, d):\n    q = p + d\n    if q < 0 or q >= CELLS:\n        sys.stdout.buffer.flush()\n");
+                    out.push_str("        sys.stderr.write('tape pointer out of bounds\\n')\n        sys.exit(2)\n    return q\n");
+                }
+                out.push_str("\n\ndef main():\n    t = [0] * CELLS\n    p = 0\n    out = sys.stdout.buffer\n");
+            },
+        }
+        out
+    }
+
+    fn epilogue(&self) -> String {
+        match self.lang {
+            Lang::C => "    return 0;\n}\n",
+            Lang::Rust => "    out.flush().unwrap();\n}\n",
+            Lang::Js => "    flush();\n}\n\nmain();\n",
+            Lang::Python => "    out.flush()\n\n\nmain()\n",
+        }.to_string()
+    }
+
+    /* index of the cell disp away from p, a pointer move through mv() on a bounded tape */
+    fn target(&self, disp: isize) -> String {
+        match (self.bounded(), self.lang) {
+            (false, _) => format!("(p + {}) % CELLS", disp.rem_euclid(self.cells as isize)),
+            (true, Lang::Rust) => format!("mv(p, {}, &mut out)", disp),
+            (true, _) => format!("mv(p, {})", disp),
+        }
+    }
+
+    /* Incr adds a signed byte to wider cells, print it as an add or a subtract */
+    fn incr(&self, rhs: u8) -> (bool, i64) {
+        let rhs = match self.prog.cell_width {
+            CellWidth::W8 if rhs > 128 => rhs as i64 - 256,
+            CellWidth::W8 => rhs as i64,
+            _ => rhs as i8 as i64,
+        };
+        (rhs >= 0, rhs.abs())
+    }
+
+    /* lines for one op, nested lines carry their own extra indentation */
+    fn op(&self, op: BFIsa) -> Vec<String> {
+        let unchanged = self.prog.eof == EofPolicy::Unchanged;
+        match (self.lang, op) {
+            /* folded moves can cancel out */
+            (_, BFIsa::Mv(disp)) if disp.rem_euclid(self.cells as isize) == 0 && (disp == 0 || !self.bounded()) => vec![],
+            (Lang::Python, BFIsa::Ret(_)) => vec![],
+
+            (Lang::C, BFIsa::Incr(rhs)) | (Lang::Js, BFIsa::Incr(rhs)) => match self.incr(rhs) {
+                (true, n) => vec![format!("t[p] += {};", n)],
+                (false, n) => vec![format!("t[p] -= {};", n)],
+            },
+            (Lang::C, BFIsa::Out) => vec!["putchar((unsigned char)t[p]);".into()],
+            (Lang::C, BFIsa::In) => vec![if unchanged { "t[p] = rd(t[p]);" } else { "t[p] = rd();" }.into()],
+            (Lang::C, BFIsa::Mv(disp)) | (Lang::Js, BFIsa::Mv(disp)) => vec![format!("p = {};", self.target(disp))],
+            (Lang::C, BFIsa::LoopSetZero) | (Lang::Js, BFIsa::LoopSetZero) => vec!["t[p] = 0;".into()],
+            (Lang::C, BFIsa::LoopMvData(disp)) => vec![
+                "if (t[p]) {".into(),
+                format!("    size_t q = {};", self.target(disp)),
+                "    t[q] += t[p];".into(),
+                "    t[p] = 0;".into(),
+                "}".into(),
+            ],
+            (Lang::C, BFIsa::LoopMvPtr(disp)) | (Lang::Js, BFIsa::LoopMvPtr(disp)) =>
+                vec![format!("while (t[p]) {{ p = {}; }}", self.target(disp))],
+            (Lang::C, BFIsa::Jmp(_)) | (Lang::Js, BFIsa::Jmp(_)) => vec!["while (t[p]) {".into()],
+            (Lang::C, BFIsa::Ret(_)) | (Lang::Js, BFIsa::Ret(_)) | (Lang::Rust, BFIsa::Ret(_)) => vec!["}".into()],
+
+            (Lang::Rust, BFIsa::Incr(rhs)) => match self.incr(rhs) {
+                (true, n) => vec![format!("t[p] = t[p].wrapping_add({});", n)],
+                (false, n) => vec![format!("t[p] = t[p].wrapping_sub({});", n)],
+            },
+            (Lang::Rust, BFIsa::Out) => vec!["out.write_all(&[t[p] as u8]).unwrap();".into()],
+            (Lang::Rust, BFIsa::In) =>
+                vec![format!("t[p] = rd(&mut input, &mut out{});", if unchanged { ", t[p]" } else { "" })],
+            (Lang::Rust, BFIsa::Mv(disp)) => vec![format!("p = {};", self.target(disp))],
+            (Lang::Rust, BFIsa::LoopSetZero) => vec!["t[p] = 0;".into()],
+            (Lang::Rust, BFIsa::LoopMvData(disp)) => vec![
+                "if t[p] != 0 {".into(),
+                format!("    let q = {};", self.target(disp)),
+                "    t[q] = t[q].wrapping_add(t[p]);".into(),
+                "    t[p] = 0;".into(),
+                "}".into(),
+            ],
+            (Lang::Rust, BFIsa::LoopMvPtr(disp)) => vec![
+                "while t[p] != 0 {".into(),
+                format!("    p = {};", self.target(disp)),
+                "}".into(),
+            ],
+            (Lang::Rust, BFIsa::Jmp(_)) => vec!["while t[p] != 0 {".into()],
+
+            (Lang::Js, BFIsa::Out) => vec!["put(t[p]);".into()],
+            (Lang::Js, BFIsa::In) => vec![if unchanged { "t[p] = rd(t[p]);" } else { "t[p] = rd();" }.into()],
+            (Lang::Js, BFIsa::LoopMvData(disp)) => vec![
+                "if (t[p]) {".into(),
+                format!("    const q = {};", self.target(disp)),
+                "    t[q] += t[p];".into(),
+                "    t[p] = 0;".into(),
+                "}".into(),
+            ],
+
+            (Lang::Python, BFIsa::Incr(rhs)) => match self.incr(rhs) {
+                (true, n) => vec![format!("t[p] = (t[p] + {}) & MASK", n)],
+                (false, n) => vec![format!("t[p] = (t[p] - {}) & MASK", n)],
+            },
+            (Lang::Python, BFIsa::Out) => vec!["out.write(bytes((t[p] & 0xff,)))".into()],
+            (Lang::Python, BFIsa::In) => vec![if unchanged { "t[p] = rd(t[p])" } else { "t[p] = rd()" }.into()],
+            (Lang::Python, BFIsa::Mv(disp)) => vec![format!("p = {}", self.target(disp))],
+            (Lang::Python, BFIsa::LoopSetZero) => vec!["t[p] = 0".into()],
+            (Lang::Python, BFIsa::LoopMvData(disp)) => vec![
+                "if t[p]:".into(),
+                format!("    q = {}", self.target(disp)),
+                "    t[q] = (t[q] + t[p]) & MASK".into(),
+                "    t[p] = 0".into(),
+            ],
+            (Lang::Python, BFIsa::LoopMvPtr(disp)) => vec![
+                "while t[p]:".into(),
+                format!("    p = {}", self.target(disp)),
+            ],
+            (Lang::Python, BFIsa::Jmp(_)) => vec!["while t[p]:".into()],
+        }
+    }
+
+    fn body(&self, out: &mut String) {
+        let mut depth = 1;
+        /* output length after each open loop's header, a Python loop that emitted nothing since needs a pass */
+        let mut opened = Vec::new();
+        for (idx, op) in self.prog.txt.iter().enumerate() {
+            if let BFIsa::Ret(_) = op {
+                if opened.pop() == Some(out.len()) && self.lang == Lang::Python {
+                    out.push_str(&"    ".repeat(depth));
+                    out.push_str("pass\n");
+                }
+                depth -= 1;
+            }
+            for (line_no, line) in self.op(*op).iter().enumerate() {
+                out.push_str(&"    ".repeat(depth));
+                out.push_str(line);
+                if self.spans && line_no == 0 {
+                    let (line, col) = self.prog.srcmap.line_col(self.prog.spans[idx].start);
+                    write!(out, "  {} {}:{}", self.lang.comment(), line, col).unwrap();
+                }
+                out.push('\n');
+            }
+            if let BFIsa::Jmp(_) = op {
+                depth += 1;
+                opened.push(out.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,286 @@
+/*
+ * WebAssembly backend. The byte code is lowered once to a list of
+ * instructions that is printed as text (wat()) or encoded as a binary module
+ * (wasm()), without any wasm tooling. Module layout, fixed for every
+ * program so hosts can rely on it:
+ *
+ *   func 0   import "env" "putchar" (param i32)        low byte of the cell for '.'
+ *   func 1   import "env" "getchar" (result i32)       next input byte, or -1 at the end of input
+ *   func 2   export "run" (result i32)                 runs the program, returns 0, or 1 when the
+ *                                                      pointer leaves a bounded tape
+ *   memory 0 export "memory"                           the tape at address 0, little endian cells,
+ *                                                      min = max = enough 64KiB pages for the tape
+ *
+ * The pointer starts at cell 0 and is not kept between calls to run().
+ * Cell width, tape model and EOF policy are compiled in.
+ */
+
+use crate::{BFIsa, CellWidth, EofPolicy, ProgramState, TapeModel};
+use std::fmt::Write;
+
+const PAGE: usize = 65536;
+
+/* locals of run() */
+const P: u32 = 0;   /* pointer, as a byte address */
+const C: u32 = 1;   /* getchar() result */
+const Q: u32 = 2;   /* target of LoopMvData */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Ins {
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Call(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    I32Const(i32),
+    I32Eqz,
+    I32GeS,
+    I32GeU,
+    I32Add,
+    I32RemU,
+    I32And,
+    Load,       /* a cell, of the module's width */
+    Store,
+}
+
+fn pages(prog: &ProgramState) -> usize {
+    prog.heap.len().div_ceil(PAGE).max(1)
+}
+
+/* local dst = local p moved by disp cells, returning 1 from run() when it leaves a bounded tape */
+fn offset(prog: &ProgramState, code: &mut Vec<Ins>, dst: u32, disp: isize) {
+    let len = prog.heap.len() as isize;
+    let disp = disp * prog.cell_width.bytes() as isize;
+    match prog.tape {
+        TapeModel::Wrap => {
+            code.extend([Ins::LocalGet(P), Ins::I32Const(disp.rem_euclid(len) as i32), Ins::I32Add]);
+            if (len as usize).is_power_of_two() {
+                code.extend([Ins::I32Const(len as i32 - 1), Ins::I32And]);
+            } else {
+                code.extend([Ins::I32Const(len as i32), Ins::I32RemU]);
+            }
+            code.push(Ins::LocalSet(dst));
+        },
+        /* a move below 0 wraps to a large unsigned address */
+        TapeModel::Bounded => code.extend([
+            Ins::LocalGet(P), Ins::I32Const(disp as i32), Ins::I32Add, Ins::LocalSet(dst),
+            Ins::LocalGet(dst), Ins::I32Const(len as i32), Ins::I32GeU,
+            Ins::If, Ins::I32Const(1), Ins::Return, Ins::End,
+        ]),
+    }
+}
+
+/* The body of run() */
+fn lower(prog: &ProgramState) -> Vec<Ins> {
+    let mut code = Vec::new();
+    for op in prog.txt.iter() {
+        match *op {
+            BFIsa::Incr(rhs) => {
+                let rhs = match prog.cell_width {
+                    CellWidth::W8 => rhs as i32,
+                    _ => rhs as i8 as i32,
+                };
+                code.extend([Ins::LocalGet(P), Ins::LocalGet(P), Ins::Load, Ins::I32Const(rhs), Ins::I32Add, Ins::Store]);
+            },
+            BFIsa::Out => code.extend([Ins::LocalGet(P), Ins::Load, Ins::I32Const(255), Ins::I32And, Ins::Call(0)]),
+            BFIsa::In => {
+                code.extend([
+                    Ins::Call(1), Ins::LocalSet(C),
+                    Ins::LocalGet(C), Ins::I32Const(0), Ins::I32GeS,
+                    Ins::If, Ins::LocalGet(P), Ins::LocalGet(C), Ins::Store,
+                ]);
+                match prog.eof {
+                    EofPolicy::Zero => code.extend([Ins::Else, Ins::LocalGet(P), Ins::I32Const(0), Ins::Store]),
+                    EofPolicy::MinusOne => code.extend([Ins::Else, Ins::LocalGet(P), Ins::I32Const(-1), Ins::Store]),
+                    EofPolicy::Unchanged => (),
+                }
+                code.push(Ins::End);
+            },
+            BFIsa::Mv(0) => (),
+            BFIsa::Mv(disp) => offset(prog, &mut code, P, disp),
+            BFIsa::LoopSetZero => code.extend([Ins::LocalGet(P), Ins::I32Const(0), Ins::Store]),
+            BFIsa::LoopMvData(disp) => {
+                code.extend([Ins::LocalGet(P), Ins::Load, Ins::If]);
+                offset(prog, &mut code, Q, disp);
+                code.extend([
+                    Ins::LocalGet(Q), Ins::LocalGet(Q), Ins::Load, Ins::LocalGet(P), Ins::Load, Ins::I32Add, Ins::Store,
+                    Ins::LocalGet(P), Ins::I32Const(0), Ins::Store,
+                    Ins::End,
+                ]);
+            },
+            BFIsa::LoopMvPtr(disp) => {
+                code.extend([Ins::Block, Ins::Loop, Ins::LocalGet(P), Ins::Load, Ins::I32Eqz, Ins::BrIf(1)]);
+                offset(prog, &mut code, P, disp);
+                code.extend([Ins::Br(0), Ins::End, Ins::End]);
+            },
+            BFIsa::Jmp(_) => code.extend([Ins::Block, Ins::Loop, Ins::LocalGet(P), Ins::Load, Ins::I32Eqz, Ins::BrIf(1)]),
+            BFIsa::Ret(_) => code.extend([Ins::Br(0), Ins::End, Ins::End]),
+        }
+    }
+    code.push(Ins::I32Const(0));
+    code
+}
+
+/* Text format, one instruction per line */
+pub fn wat(prog: &ProgramState) -> String {
+    let (load, store) = match prog.cell_width {
+        CellWidth::W8 => ("i32.load8_u", "i32.store8"),
+        CellWidth::W16 => ("i32.load16_u", "i32.store16"),
+        CellWidth::W32 => ("i32.load", "i32.store"),
+    };
+    let local = |idx: u32| ["$p", "$c", "$q"][idx as usize];
+
+    let mut out = String::new();
+    out.push_str("(module\n");
+    out.push_str("  (import \"env\" \"putchar\" (func $putchar (param i32)))\n");
+    out.push_str("  (import \"env\" \"getchar\" (func $getchar (result i32)))\n");
+    writeln!(out, "  (memory (export \"memory\") {} {})", pages(prog), pages(prog)).unwrap();
+    out.push_str("  (func (export \"run\") (result i32)\n    (local $p i32) (local $c i32) (local $q i32)\n");
+
+    let mut depth = 2;
+    for ins in lower(prog) {
+        if matches!(ins, Ins::End | Ins::Else) {
+            depth -= 1;
+        }
+        out.push_str(&"  ".repeat(depth));
+        match ins {
+            Ins::Block => out.push_str("block"),
+            Ins::Loop => out.push_str("loop"),
+            Ins::If => out.push_str("if"),
+            Ins::Else => out.push_str("else"),
+            Ins::End => out.push_str("end"),
+            Ins::Br(depth) => write!(out, "br {}", depth).unwrap(),
+            Ins::BrIf(depth) => write!(out, "br_if {}", depth).unwrap(),
+            Ins::Return => out.push_str("return"),
+            Ins::Call(func) => out.push_str(["call $putchar", "call $getchar"][func as usize]),
+            Ins::LocalGet(idx) => write!(out, "local.get {}", local(idx)).unwrap(),
+            Ins::LocalSet(idx) => write!(out, "local.set {}", local(idx)).unwrap(),
+            Ins::I32Const(val) => write!(out, "i32.const {}", val).unwrap(),
+            Ins::I32Eqz => out.push_str("i32.eqz"),
+            Ins::I32GeS => out.push_str("i32.ge_s"),
+            Ins::I32GeU => out.push_str("i32.ge_u"),
+            Ins::I32Add => out.push_str("i32.add"),
+            Ins::I32RemU => out.push_str("i32.rem_u"),
+            Ins::I32And => out.push_str("i32.and"),
+            Ins::Load => out.push_str(load),
+            Ins::Store => out.push_str(store),
+        }
+        out.push('\n');
+        if matches!(ins, Ins::Block | Ins::Loop | Ins::If | Ins::Else) {
+            depth += 1;
+        }
+    }
+    out.push_str("  )\n)\n");
+    out
+}
+
+fn uleb(out: &mut Vec<u8>, mut val: u64) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut val: i64) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if (val == 0 && byte & 0x40 == 0) || (val == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, body: &[u8]) {
+    out.push(id);
+    uleb(out, body.len() as u64);
+    out.extend_from_slice(body);
+}
+
+/* Binary format, the same module as wat() */
+pub fn wasm(prog: &ProgramState) -> Vec<u8> {
+    /* opcode and alignment exponent of cell loads and stores */
+    let (load, store, align) = match prog.cell_width {
+        CellWidth::W8 => (0x2d, 0x3a, 0),
+        CellWidth::W16 => (0x2f, 0x3b, 1),
+        CellWidth::W32 => (0x28, 0x36, 2),
+    };
+
+    let mut out = b"\0asm".to_vec();
+    out.extend_from_slice(&1u32.to_le_bytes());
+
+    /* types: 0 (i32) -> (), 1 () -> i32 */
+    section(&mut out, 1, &[2, 0x60, 1, 0x7f, 0, 0x60, 0, 1, 0x7f]);
+
+    let mut imports = vec![2];
+    for (func, ty) in [("putchar", 0), ("getchar", 1)] {
+        name(&mut imports, "env");
+        name(&mut imports, func);
+        imports.extend_from_slice(&[0, ty]);
+    }
+    section(&mut out, 2, &imports);
+    section(&mut out, 3, &[1, 1]);
+
+    let mut memory = vec![1, 1];
+    uleb(&mut memory, pages(prog) as u64);
+    uleb(&mut memory, pages(prog) as u64);
+    section(&mut out, 5, &memory);
+
+    let mut exports = vec![2];
+    name(&mut exports, "memory");
+    exports.extend_from_slice(&[2, 0]);
+    name(&mut exports, "run");
+    exports.extend_from_slice(&[0, 2]);
+    section(&mut out, 7, &exports);
+
+    let mut body = vec![1, 3, 0x7f];
+    for ins in lower(prog) {
+        match ins {
+            Ins::Block => body.extend_from_slice(&[0x02, 0x40]),
+            Ins::Loop => body.extend_from_slice(&[0x03, 0x40]),
+            Ins::If => body.extend_from_slice(&[0x04, 0x40]),
+            Ins::Else => body.push(0x05),
+            Ins::End => body.push(0x0b),
+            Ins::Br(depth) => { body.push(0x0c); uleb(&mut body, depth as u64) },
+            Ins::BrIf(depth) => { body.push(0x0d); uleb(&mut body, depth as u64) },
+            Ins::Return => body.push(0x0f),
+            Ins::Call(func) => { body.push(0x10); uleb(&mut body, func as u64) },
+            Ins::LocalGet(idx) => { body.push(0x20); uleb(&mut body, idx as u64) },
+            Ins::LocalSet(idx) => { body.push(0x21); uleb(&mut body, idx as u64) },
+            Ins::I32Const(val) => { body.push(0x41); sleb(&mut body, val as i64) },
+            Ins::I32Eqz => body.push(0x45),
+            Ins::I32GeS => body.push(0x4e),
+            Ins::I32GeU => body.push(0x4f),
+            Ins::I32Add => body.push(0x6a),
+            Ins::I32RemU => body.push(0x70),
+            Ins::I32And => body.push(0x71),
+            Ins::Load => body.extend_from_slice(&[load, align, 0]),
+            Ins::Store => body.extend_from_slice(&[store, align, 0]),
+        }
+    }
+    body.push(0x0b);
+
+    let mut code = vec![1];
+    uleb(&mut code, body.len() as u64);
+    code.extend_from_slice(&body);
+    section(&mut out, 10, &code);
+    out
+}